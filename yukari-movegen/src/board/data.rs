@@ -1,15 +1,18 @@
+#[cfg(feature = "eval")]
+use super::eval::Eval;
 use super::{
     bitlist::{Bitlist, BitlistArray},
-    eval::Eval,
     index::{PieceIndex, PieceIndexArray},
     piecelist::Piecelist,
     piecemask::Piecemask,
     zobrist::Zobrist,
 };
+#[cfg(feature = "eval")]
+use crate::File;
 use crate::{
     colour::Colour,
     piece::Piece,
-    square::{Direction, Square, Square16x8}, File,
+    square::{Direction, Square, Square16x8},
 };
 
 #[allow(clippy::module_name_repetitions)]
@@ -22,6 +25,7 @@ pub struct BoardData {
     /// Zobrist hash.
     hash: u64,
     /// Evaluation state.
+    #[cfg(feature = "eval")]
     eval: Eval,
 }
 
@@ -33,6 +37,7 @@ impl Default for BoardData {
 
 impl BoardData {
     /// Create a new board.
+    #[cfg_attr(not(feature = "eval"), allow(clippy::missing_const_for_fn))]
     pub fn new() -> Self {
         Self {
             bitlist: BitlistArray::new(),
@@ -40,6 +45,7 @@ impl BoardData {
             index: PieceIndexArray::new(),
             piecemask: Piecemask::new(),
             hash: 0,
+            #[cfg(feature = "eval")]
             eval: Eval::new(),
         }
     }
@@ -122,11 +128,14 @@ impl BoardData {
         self.index.add_piece(piece_index, square);
         Zobrist::add_piece(colour, self.piece_from_bit(piece_index), square, &mut self.hash);
 
-        let white_king = self.king_square(Colour::White);
-        let black_king = self.king_square(Colour::Black);
-        self.eval.add_piece(piece, square, colour, white_king, black_king);
-
         if update {
+            #[cfg(feature = "eval")]
+            {
+                let white_king = self.king_square(Colour::White);
+                let black_king = self.king_square(Colour::Black);
+                self.eval.add_piece(piece, square, colour, white_king, black_king);
+            }
+
             self.update_attacks(square, piece_index, piece, true, None);
             self.update_sliders(square, false);
         }
@@ -141,16 +150,20 @@ impl BoardData {
         self.index.remove_piece(piece_index, square);
         Zobrist::remove_piece(piece_index.colour(), piece, square, &mut self.hash);
 
-        let white_king = self.king_square(Colour::White);
-        let black_king = self.king_square(Colour::Black);
-        self.eval.remove_piece(piece, square, piece_index.colour(), white_king, black_king);
-
         if update {
+            #[cfg(feature = "eval")]
+            {
+                let white_king = self.king_square(Colour::White);
+                let black_king = self.king_square(Colour::Black);
+                self.eval.remove_piece(piece, square, piece_index.colour(), white_king, black_king);
+            }
+
             self.update_attacks(square, piece_index, piece, false, None);
             self.update_sliders(square, true);
         }
     }
 
+    #[cfg(feature = "eval")]
     pub fn rebuild_accumulators(&mut self) {
         let white_king = self.king_square(Colour::White);
         let black_king = self.king_square(Colour::Black);
@@ -184,6 +197,21 @@ impl BoardData {
         self.index.move_piece(piece_index, from_square, to_square);
         Zobrist::move_piece(piece_index.colour(), piece, from_square, to_square, &mut self.hash);
 
+        #[cfg(feature = "eval")]
+        self.update_eval_for_move(piece_index, piece, from_square, to_square);
+
+        if slide_dir.is_some() {
+            self.bitlist.remove_piece(to_square, piece_index);
+        }
+        self.update_attacks(to_square, piece_index, piece, true, slide_dir);
+        self.update_sliders(to_square, false);
+
+        debug_assert!(!self.bitlist[to_square].contains(piece_index.into()), "piece on {to_square} cannot attack itself");
+    }
+
+    /// Incrementally update the accumulators for a piece moving between squares.
+    #[cfg(feature = "eval")]
+    fn update_eval_for_move(&mut self, piece_index: PieceIndex, piece: Piece, from_square: Square, to_square: Square) {
         let white_king = self.king_square(Colour::White);
         let black_king = self.king_square(Colour::Black);
 
@@ -203,14 +231,6 @@ impl BoardData {
         } else {
             self.eval.move_piece(piece, from_square, to_square, piece_index.colour(), white_king, black_king);
         }
-
-        if slide_dir.is_some() {
-            self.bitlist.remove_piece(to_square, piece_index);
-        }
-        self.update_attacks(to_square, piece_index, piece, true, slide_dir);
-        self.update_sliders(to_square, false);
-
-        debug_assert!(!self.bitlist[to_square].contains(piece_index.into()), "piece on {to_square} cannot attack itself");
     }
 
     /// Set the en-passant square.
@@ -233,11 +253,30 @@ impl BoardData {
         Zobrist::toggle_side(&mut self.hash);
     }
 
+    /// Borrow the evaluation state.
+    #[cfg(all(test, feature = "eval"))]
+    pub(super) const fn eval_state(&self) -> &Eval {
+        &self.eval
+    }
+
     /// Evaluation from the perspective of `colour`.
+    #[cfg(feature = "eval")]
     pub fn eval(&self, colour: Colour) -> i32 {
         self.eval.get(self.piecemask().occupied().count_ones() as usize, colour)
     }
 
+    /// The network output bucket selected for this position.
+    #[cfg(feature = "eval")]
+    pub const fn output_bucket(&self) -> usize {
+        Eval::output_bucket(self.piecemask().occupied().count_ones() as usize)
+    }
+
+    /// Evaluation from the perspective of `colour`, using a specific output bucket.
+    #[cfg(feature = "eval")]
+    pub fn eval_in_bucket(&self, colour: Colour, output_bucket: usize) -> i32 {
+        self.eval.get_in_bucket(output_bucket, colour)
+    }
+
     /// Rebuild the attack set for the board.
     pub fn rebuild_attacks(&mut self) {
         for square in 0_u8..64 {
@@ -1,13 +1,11 @@
-use std::simd::{cmp::SimdOrd, i16x64, i32x64, num::SimdInt};
-
 use crate::{Colour, File, Piece, Square};
 
-const HIDDEN_SIZE: usize = 768;
-const OUTPUT_BUCKETS: usize = 8;
+pub const HIDDEN_SIZE: usize = 768;
+pub const OUTPUT_BUCKETS: usize = 8;
 const DIVISOR: usize = 32_usize.div_ceil(OUTPUT_BUCKETS);
-const SCALE: i32 = 400;
-const QA: i16 = 255;
-const QB: i16 = 64;
+pub const SCALE: i32 = 400;
+pub const QA: i16 = 255;
+pub const QB: i16 = 64;
 
 /// This is the quantised format that yukari uses.
 #[repr(C)]
@@ -30,28 +28,14 @@ impl Network {
     /// Calculates the output of the network, starting from the already
     /// calculated hidden layer (done efficiently during makemoves).
     pub fn evaluate(&self, us: &Accumulator, them: &Accumulator, output_bucket: usize) -> i32 {
-        // Initialise output with bias.
-        let mut output = i32x64::splat(0);
-        let min = i16x64::splat(0);
-        let max = i16x64::splat(QA);
-
-        // Side-To-Move Accumulator -> Output.
-        for (input, weight) in us.vals.array_chunks::<64>().zip(self.output_weights[output_bucket][0].vals.array_chunks::<64>()) {
-            // Squared Clipped `ReLU` - Activation Function.
-            // Note that this takes the i16s in the accumulator to i32s.
-            let input = i16x64::from_array(*input).simd_clamp(min, max);
-            let weight = input * i16x64::from_array(*weight);
-            output += input.cast::<i32>() * weight.cast::<i32>();
-        }
-
-        // Not-Side-To-Move Accumulator -> Output.
-        for (input, weight) in them.vals.array_chunks::<64>().zip(self.output_weights[output_bucket][1].vals.array_chunks::<64>()) {
-            let input = i16x64::from_array(*input).simd_clamp(min, max);
-            let weight = input * i16x64::from_array(*weight);
-            output += input.cast::<i32>() * weight.cast::<i32>();
-        }
+        let weights = &self.output_weights[output_bucket];
+        let output = activate_and_dot(us, &weights[0]).wrapping_add(activate_and_dot(them, &weights[1]));
+        self.finish(output, output_bucket)
+    }
 
-        let mut output = (output.reduce_sum() / i32::from(QA)) + i32::from(self.output_bias[output_bucket]);
+    /// Turns the raw output-layer sum into a centipawn score.
+    fn finish(&self, output: i32, output_bucket: usize) -> i32 {
+        let mut output = (output / i32::from(QA)) + i32::from(self.output_bias[output_bucket]);
 
         // Apply eval scale.
         output *= SCALE;
@@ -61,6 +45,99 @@ impl Network {
     }
 }
 
+/// Squared Clipped `ReLU` of `input`, dotted with `weight`.
+///
+/// All implementations compute `clamp(x) * (clamp(x) * w as i16)` with wrapping `i16` and `i32` arithmetic,
+/// so they produce bit-identical results.
+#[cfg(feature = "simd")]
+fn activate_and_dot(input: &Accumulator, weight: &Accumulator) -> i32 {
+    activate_and_dot_simd(input, weight)
+}
+
+#[cfg(all(not(feature = "simd"), target_arch = "x86_64", target_feature = "avx2"))]
+fn activate_and_dot(input: &Accumulator, weight: &Accumulator) -> i32 {
+    // SAFETY: this is only compiled when AVX2 is enabled for the whole program.
+    unsafe { activate_and_dot_avx2(input, weight) }
+}
+
+#[cfg(not(any(feature = "simd", all(target_arch = "x86_64", target_feature = "avx2"))))]
+fn activate_and_dot(input: &Accumulator, weight: &Accumulator) -> i32 {
+    activate_and_dot_scalar(input, weight)
+}
+
+/// Portable scalar implementation, and the reference the others are tested against.
+#[cfg(any(test, not(any(feature = "simd", all(target_arch = "x86_64", target_feature = "avx2")))))]
+#[allow(clippy::cast_possible_truncation)]
+fn activate_and_dot_scalar(input: &Accumulator, weight: &Accumulator) -> i32 {
+    let mut output = 0_i32;
+    for (&input, &weight) in input.vals.iter().zip(&weight.vals) {
+        // Note that `input * weight` deliberately wraps in i16, as the vector implementations do.
+        let input = input.clamp(0, QA);
+        let weight = input.wrapping_mul(weight);
+        output = output.wrapping_add(i32::from(input) * i32::from(weight));
+    }
+    output
+}
+
+/// # Safety
+///
+/// The CPU must support AVX2. Tests build this whatever the target features, to check it wherever the CPU allows.
+#[cfg(all(target_arch = "x86_64", any(test, target_feature = "avx2")))]
+#[target_feature(enable = "avx2")]
+// The loads are aligned because `Accumulator` is.
+#[allow(clippy::cast_ptr_alignment)]
+unsafe fn activate_and_dot_avx2(input: &Accumulator, weight: &Accumulator) -> i32 {
+    use std::arch::x86_64::{
+        __m256i, _mm256_add_epi32, _mm256_castsi256_si128, _mm256_extracti128_si256, _mm256_load_si256, _mm256_madd_epi16, _mm256_max_epi16,
+        _mm256_min_epi16, _mm256_mullo_epi16, _mm256_set1_epi16, _mm256_setzero_si256, _mm_add_epi32, _mm_cvtsi128_si32,
+        _mm_shuffle_epi32,
+    };
+
+    // SAFETY: the caller guarantees AVX2, and `Accumulator` is 64-byte aligned.
+    unsafe {
+        let min = _mm256_setzero_si256();
+        let max = _mm256_set1_epi16(QA);
+        let mut output = _mm256_setzero_si256();
+
+        let (inputs, _) = input.vals.as_chunks::<16>();
+        let (weights, _) = weight.vals.as_chunks::<16>();
+        for (input, weight) in inputs.iter().zip(weights) {
+            let input = _mm256_load_si256(input.as_ptr().cast::<__m256i>());
+            let weight = _mm256_load_si256(weight.as_ptr().cast::<__m256i>());
+            let input = _mm256_min_epi16(_mm256_max_epi16(input, min), max);
+            let weight = _mm256_mullo_epi16(input, weight);
+            // `input` is never negative, so the pairwise sum of products cannot overflow.
+            output = _mm256_add_epi32(output, _mm256_madd_epi16(input, weight));
+        }
+
+        let output = _mm_add_epi32(_mm256_castsi256_si128(output), _mm256_extracti128_si256::<1>(output));
+        let output = _mm_add_epi32(output, _mm_shuffle_epi32::<0b0100_1110>(output));
+        let output = _mm_add_epi32(output, _mm_shuffle_epi32::<0b1011_0001>(output));
+        _mm_cvtsi128_si32(output)
+    }
+}
+
+#[cfg(feature = "simd")]
+fn activate_and_dot_simd(input: &Accumulator, weight: &Accumulator) -> i32 {
+    use std::simd::{cmp::SimdOrd, i16x64, i32x64, num::SimdInt};
+
+    let mut output = i32x64::splat(0);
+    let min = i16x64::splat(0);
+    let max = i16x64::splat(QA);
+
+    let (inputs, _) = input.vals.as_chunks::<64>();
+    let (weights, _) = weight.vals.as_chunks::<64>();
+    for (input, weight) in inputs.iter().zip(weights) {
+        // Squared Clipped `ReLU` - Activation Function.
+        // Note that this takes the i16s in the accumulator to i32s.
+        let input = i16x64::from_array(*input).simd_clamp(min, max);
+        let weight = input * i16x64::from_array(*weight);
+        output += input.cast::<i32>() * weight.cast::<i32>();
+    }
+
+    output.reduce_sum()
+}
+
 /// A column of the feature-weights matrix.
 /// Note the `align(64)`.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -110,8 +187,17 @@ impl Eval {
         }
     }
 
+    /// The output bucket used for a position with `piece_count` pieces.
+    pub const fn output_bucket(piece_count: usize) -> usize {
+        (piece_count - 2) / DIVISOR
+    }
+
     pub fn get(&self, piece_count: usize, colour: Colour) -> i32 {
-        let output_bucket = (piece_count - 2) / DIVISOR;
+        self.get_in_bucket(Self::output_bucket(piece_count), colour)
+    }
+
+    /// Evaluation using a specific output bucket, rather than the one the piece count selects.
+    pub fn get_in_bucket(&self, output_bucket: usize, colour: Colour) -> i32 {
         if colour == Colour::White {
             NNUE.evaluate(&self.white, &self.black, output_bucket)
         } else {
@@ -119,72 +205,48 @@ impl Eval {
         }
     }
 
-    pub fn add_piece_for_acc(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square, white_acc: bool) {
+    /// Input feature indices of a piece, from white's and black's perspective respectively.
+    pub fn feature_indices(piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square) -> (usize, usize) {
         let white_flip = if File::from(white_king) >= File::E { 7 } else { 0 };
         let black_flip = if File::from(black_king) >= File::E { 7 } else { 0 };
         let white_square = square.into_inner() as usize ^ white_flip;
         let black_square = square.flip().into_inner() as usize ^ black_flip;
 
         if colour == Colour::White {
-            if white_acc {
-                self.white.add_feature(64 * (piece as usize) + white_square, &NNUE);
-            } else {
-                self.black.add_feature(64 * (6 + piece as usize) + black_square, &NNUE);
-            }
-        } else if white_acc {
-            self.white.add_feature(64 * (6 + piece as usize) + white_square, &NNUE);
+            (64 * (piece as usize) + white_square, 64 * (6 + piece as usize) + black_square)
         } else {
-            self.black.add_feature(64 * (piece as usize) + black_square, &NNUE);
+            (64 * (6 + piece as usize) + white_square, 64 * (piece as usize) + black_square)
         }
     }
 
-    pub fn add_piece(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square) {
-        let white_flip = if File::from(white_king) >= File::E { 7 } else { 0 };
-        let black_flip = if File::from(black_king) >= File::E { 7 } else { 0 };
-        let white_square = square.into_inner() as usize ^ white_flip;
-        let black_square = square.flip().into_inner() as usize ^ black_flip;
-
-        if colour == Colour::White {
-            self.white.add_feature(64 * (piece as usize) + white_square, &NNUE);
-            self.black.add_feature(64 * (6 + piece as usize) + black_square, &NNUE);
+    pub fn add_piece_for_acc(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square, white_acc: bool) {
+        let (white_feature, black_feature) = Self::feature_indices(piece, square, colour, white_king, black_king);
+        if white_acc {
+            self.white.add_feature(white_feature, &NNUE);
         } else {
-            self.black.add_feature(64 * (piece as usize) + black_square, &NNUE);
-            self.white.add_feature(64 * (6 + piece as usize) + white_square, &NNUE);
+            self.black.add_feature(black_feature, &NNUE);
         }
     }
 
-    pub fn remove_piece_for_acc(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square, white_acc: bool) {
-        let white_flip = if File::from(white_king) >= File::E { 7 } else { 0 };
-        let black_flip = if File::from(black_king) >= File::E { 7 } else { 0 };
-        let white_square = square.into_inner() as usize ^ white_flip;
-        let black_square = square.flip().into_inner() as usize ^ black_flip;
+    pub fn add_piece(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square) {
+        let (white_feature, black_feature) = Self::feature_indices(piece, square, colour, white_king, black_king);
+        self.white.add_feature(white_feature, &NNUE);
+        self.black.add_feature(black_feature, &NNUE);
+    }
 
-        if colour == Colour::White {
-            if white_acc {
-                self.white.remove_feature(64 * (piece as usize) + white_square, &NNUE);
-            } else {
-                self.black.remove_feature(64 * (6 + piece as usize) + black_square, &NNUE);
-            }
-        } else if white_acc {
-            self.white.remove_feature(64 * (6 + piece as usize) + white_square, &NNUE);
+    pub fn remove_piece_for_acc(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square, white_acc: bool) {
+        let (white_feature, black_feature) = Self::feature_indices(piece, square, colour, white_king, black_king);
+        if white_acc {
+            self.white.remove_feature(white_feature, &NNUE);
         } else {
-            self.black.remove_feature(64 * (piece as usize) + black_square, &NNUE);
+            self.black.remove_feature(black_feature, &NNUE);
         }
     }
 
     pub fn remove_piece(&mut self, piece: Piece, square: Square, colour: Colour, white_king: Square, black_king: Square) {
-        let white_flip = if File::from(white_king) >= File::E { 7 } else { 0 };
-        let black_flip = if File::from(black_king) >= File::E { 7 } else { 0 };
-        let white_square = square.into_inner() as usize ^ white_flip;
-        let black_square = square.flip().into_inner() as usize ^ black_flip;
-
-        if colour == Colour::White {
-            self.white.remove_feature(64 * (piece as usize) + white_square, &NNUE);
-            self.black.remove_feature(64 * (6 + piece as usize) + black_square, &NNUE);
-        } else {
-            self.black.remove_feature(64 * (piece as usize) + black_square, &NNUE);
-            self.white.remove_feature(64 * (6 + piece as usize) + white_square, &NNUE);
-        }
+        let (white_feature, black_feature) = Self::feature_indices(piece, square, colour, white_king, black_king);
+        self.white.remove_feature(white_feature, &NNUE);
+        self.black.remove_feature(black_feature, &NNUE);
     }
 
     pub fn move_piece(&mut self, piece: Piece, from_square: Square, to_square: Square, colour: Colour, white_king: Square, black_king: Square) {
@@ -192,3 +254,68 @@ impl Eval {
         self.add_piece(piece, to_square, colour, white_king, black_king);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Accumulator, OUTPUT_BUCKETS};
+    use crate::{Board, BENCH_FENS};
+
+    fn accumulators() -> Vec<(Accumulator, Accumulator)> {
+        BENCH_FENS
+            .iter()
+            .map(|fen| {
+                let board = Board::from_fen(fen).unwrap();
+                let eval = board.data.eval_state();
+                (eval.white, eval.black)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn evaluate_matches_scalar() {
+        for (white, black) in accumulators() {
+            for bucket in 0..OUTPUT_BUCKETS {
+                for (us, them) in [(&white, &black), (&black, &white)] {
+                    let weights = &super::NNUE.output_weights[bucket];
+                    let expected = super::activate_and_dot_scalar(us, &weights[0])
+                        .wrapping_add(super::activate_and_dot_scalar(them, &weights[1]));
+                    assert_eq!(super::NNUE.evaluate(us, them, bucket), super::NNUE.finish(expected, bucket));
+                }
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_matches_scalar() {
+        fn avx2(input: &Accumulator, weight: &Accumulator) -> i32 {
+            // SAFETY: only called once the CPU is known to support AVX2.
+            unsafe { super::activate_and_dot_avx2(input, weight) }
+        }
+
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        for (white, black) in accumulators() {
+            for weights in &super::NNUE.output_weights {
+                for weight in weights {
+                    assert_eq!(avx2(&white, weight), super::activate_and_dot_scalar(&white, weight));
+                    assert_eq!(avx2(&black, weight), super::activate_and_dot_scalar(&black, weight));
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn simd_matches_scalar() {
+        for (white, black) in accumulators() {
+            for weights in &super::NNUE.output_weights {
+                for weight in weights {
+                    assert_eq!(super::activate_and_dot_simd(&white, weight), super::activate_and_dot_scalar(&white, weight));
+                    assert_eq!(super::activate_and_dot_simd(&black, weight), super::activate_and_dot_scalar(&black, weight));
+                }
+            }
+        }
+    }
+}
@@ -17,6 +17,7 @@ use crate::{
 
 mod bitlist;
 mod data;
+#[cfg(feature = "eval")]
 mod eval;
 mod index;
 mod piecelist;
@@ -26,6 +27,8 @@ mod zobrist;
 
 use bitlist::Bitlist;
 use data::BoardData;
+#[cfg(feature = "eval")]
+pub use eval::{HIDDEN_SIZE, OUTPUT_BUCKETS, QA, QB, SCALE};
 pub use index::PieceIndex;
 
 /// A chess position.
@@ -51,7 +54,7 @@ impl Display for Board {
     #[allow(clippy::missing_inline_in_public_items)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for i in 0_u8..64_u8 {
-            let j = i ^ 56_u8;
+            let j = i ^ 0o70_u8;
 
             let square_colour = |s: &str| if (j & 1) ^ ((j >> 3) & 1) == 0 { s.on_green() } else { s.on_white() };
 
@@ -261,12 +264,15 @@ impl Board {
         }
 
         b.data.rebuild_attacks();
-        b.data.rebuild_accumulators();
 
         if b.illegal() {
             return None;
         }
 
+        // The accumulators need both kings on the board, so they are built last.
+        #[cfg(feature = "eval")]
+        b.data.rebuild_accumulators();
+
         Some(b)
     }
 
@@ -858,6 +864,64 @@ impl Board {
         }
     }
 
+    /// Check whether `m`, which may come from another position (e.g. a hash table move), is legal here.
+    #[must_use]
+    pub fn is_legal(&self, m: Move) -> bool {
+        let Some(mover) = self.data.piece_index(m.from) else { return false };
+        if mover.colour() != self.side {
+            return false;
+        }
+        let victim = self.data.piece_index(m.dest);
+        if victim.is_some_and(|victim| victim.colour() == self.side || self.data.piece_from_bit(victim) == Piece::King) {
+            return false;
+        }
+
+        let promotes = Rank::from(m.dest).is_relative_eighth(self.side);
+        let valid_promotion = matches!(m.prom, Some(Piece::Knight | Piece::Bishop | Piece::Rook | Piece::Queen));
+        let attacks = self.data.attacks_to(m.dest, self.side).contains(Bitlist::from_piece(mover));
+        let piece = self.data.piece_from_bit(mover);
+        let pseudo_legal = match (piece, m.kind) {
+            // These are rare enough that it isn't worth checking them separately.
+            (_, MoveType::Castle | MoveType::EnPassant) => {
+                let mut v = ArrayVec::new();
+                self.generate(&mut v);
+                return v.contains(&m);
+            }
+            (Piece::Pawn, MoveType::Normal) => {
+                !promotes && m.prom.is_none() && victim.is_none() && m.from.relative_north(self.side) == Some(m.dest)
+            }
+            (Piece::Pawn, MoveType::Promotion) => {
+                promotes && valid_promotion && victim.is_none() && m.from.relative_north(self.side) == Some(m.dest)
+            }
+            (Piece::Pawn, MoveType::DoublePush) => m.from.relative_north(self.side).is_some_and(|middle| {
+                !self.data.has_piece(middle)
+                    && middle.relative_north(self.side) == Some(m.dest)
+                    && Rank::from(m.dest).is_relative_fourth(self.side)
+                    && victim.is_none()
+            }),
+            (Piece::Pawn, MoveType::Capture) => !promotes && m.prom.is_none() && victim.is_some() && attacks,
+            (Piece::Pawn, MoveType::CapturePromotion) => promotes && valid_promotion && victim.is_some() && attacks,
+            (_, MoveType::Normal) => m.prom.is_none() && victim.is_none() && attacks,
+            (_, MoveType::Capture) => m.prom.is_none() && victim.is_some() && attacks,
+            _ => false,
+        };
+
+        if !pseudo_legal {
+            return false;
+        }
+
+        // Out of check, only the king and pinned pieces can expose the king; pinned pieces may stay on the pin ray.
+        if !self.in_check() && piece != Piece::King {
+            let pininfo = pins::PinInfo::discover(self);
+            return pininfo.pins[mover.into_inner() as usize].is_none_or(|dir| {
+                m.from.direction(m.dest).is_some_and(|move_dir| move_dir == dir || move_dir == dir.opposite())
+            });
+        }
+
+        // Otherwise, rather than reasoning about checks, see whether the move leaves the king attacked.
+        !self.make(m).illegal()
+    }
+
     #[must_use]
     #[allow(clippy::too_many_lines, clippy::missing_panics_doc)]
     pub fn static_exchange_evaluation(&self, m: Move) -> i32 {
@@ -1049,11 +1113,60 @@ impl Board {
         self.data.hash_pawns()
     }
 
+    #[cfg(feature = "eval")]
     #[must_use]
     pub fn eval(&self, colour: Colour) -> i32 {
         self.data.eval(colour)
     }
 
+    /// The network output bucket `eval` uses for this position.
+    #[cfg(feature = "eval")]
+    #[must_use]
+    pub const fn output_bucket(&self) -> usize {
+        self.data.output_bucket()
+    }
+
+    /// Evaluation from the perspective of `colour`, forcing the network to use `output_bucket`.
+    ///
+    /// # Panics
+    /// Panics if `output_bucket` is not less than `OUTPUT_BUCKETS`.
+    #[cfg(feature = "eval")]
+    #[must_use]
+    pub fn eval_in_bucket(&self, colour: Colour, output_bucket: usize) -> i32 {
+        assert!(output_bucket < OUTPUT_BUCKETS, "output bucket {output_bucket} out of range");
+        self.data.eval_in_bucket(colour, output_bucket)
+    }
+
+    /// Network input features of every piece on the board, as (white perspective, black perspective) indices.
+    #[cfg(feature = "eval")]
+    #[must_use]
+    pub fn eval_features(&self) -> Vec<(usize, usize)> {
+        let white_king = self.data.king_square(Colour::White);
+        let black_king = self.data.king_square(Colour::Black);
+        let mut features = Vec::new();
+        for square in 0..64 {
+            let square = unsafe { Square::from_u8_unchecked(square) };
+            let Some(piece_index) = self.data.piece_index(square) else { continue };
+            let piece = self.data.piece_from_bit(piece_index);
+            features.push(eval::Eval::feature_indices(piece, square, piece_index.colour(), white_king, black_king));
+        }
+        features
+    }
+
+    /// Evaluation from the perspective of `colour` with the piece on `square` removed.
+    /// Returns `None` if the square is empty or holds a king.
+    #[cfg(feature = "eval")]
+    #[must_use]
+    pub fn eval_without_piece(&self, square: Square, colour: Colour) -> Option<i32> {
+        let piece_index = self.data.piece_index(square)?;
+        if self.data.piece_from_bit(piece_index) == Piece::King {
+            return None;
+        }
+        let mut data = self.data.clone();
+        data.remove_piece(piece_index, true);
+        Some(data.eval(colour))
+    }
+
     #[must_use]
     pub fn in_check(&self) -> bool {
         !self.data.attacks_to(self.data.king_square(self.side), !self.side).empty()
@@ -1741,4 +1854,39 @@ mod tests {
         let m = find_move(&board, "e1g1");
         assert_eq!(board.static_exchange_evaluation(m), 0);
     }
+
+    #[test]
+    fn is_legal_agrees_with_generate() {
+        let fens = [
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - ",
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - ",
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - ",
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - ",
+        ];
+
+        // Every position up to a ply deep, with its legal moves.
+        let mut positions = Vec::new();
+        for fen in fens {
+            let board = Board::from_fen(fen).unwrap();
+            let mut moves = tinyvec::ArrayVec::new();
+            board.generate(&mut moves);
+            for m in moves {
+                let child = board.make(m);
+                let mut child_moves = tinyvec::ArrayVec::new();
+                child.generate(&mut child_moves);
+                positions.push((child, child_moves));
+            }
+            positions.push((board, moves));
+        }
+
+        let mut candidates: Vec<crate::Move> = positions.iter().flat_map(|(_, moves)| moves.iter().copied()).collect();
+        candidates.sort_by_key(|m| format!("{m}{}", m.kind as u8));
+        candidates.dedup();
+
+        for (board, moves) in &positions {
+            for m in &candidates {
+                assert_eq!(board.is_legal(*m), moves.contains(m), "{m} in {board}");
+            }
+        }
+    }
 }
@@ -63,8 +63,9 @@ impl Move {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 pub enum MoveType {
+    #[default]
     Normal,
     Capture,
     Castle,
@@ -73,9 +74,3 @@ pub enum MoveType {
     Promotion,
     CapturePromotion,
 }
-
-impl Default for MoveType {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
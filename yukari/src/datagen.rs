@@ -168,10 +168,16 @@ pub struct DataGen<'a, T: Write> {
     f: &'a mut T,
     rng: rand::rngs::ThreadRng,
     params: search::SearchParams,
-    tt: Vec<search::TtEntry>,
-    history: [[i16; 64]; 64],
+    tt: Vec<search::TtBucket>,
+    tt_generation: u8,
+    eval_cache: Vec<search::EvalCacheEntry>,
+    history: search::History,
     corrhist: [[i32; 16384]; 2],
     positions: usize,
+    /// Nodes after which rollout searches start no new iteration.
+    soft_nodes: u64,
+    /// Nodes after which rollout searches stop immediately.
+    hard_nodes: u64,
 }
 
 impl<'a, T: Write> DataGen<'a, T> {
@@ -181,12 +187,24 @@ impl<'a, T: Write> DataGen<'a, T> {
             rng: rand::rng(),
             params: search::SearchParams::default(),
             tt: search::allocate_tt(16),
-            history: [[0; 64]; 64],
+            tt_generation: 0,
+            eval_cache: search::allocate_eval_cache(2),
+            history: search::History::new(),
             corrhist: [[0; 16384]; 2],
             positions: 0,
+            soft_nodes: 5_000,
+            hard_nodes: 100_000,
         }
     }
 
+    /// Set the node limits of the searches used to play out games.
+    #[must_use]
+    pub const fn with_node_limits(mut self, soft_nodes: u64, hard_nodes: u64) -> Self {
+        self.soft_nodes = soft_nodes;
+        self.hard_nodes = hard_nodes;
+        self
+    }
+
     #[must_use]
     fn find_move(&self, board: &Board, from: Square, dest: Square, prom: Option<Piece>) -> Option<Move> {
         let mut moves = ArrayVec::new();
@@ -243,19 +261,40 @@ impl<'a, T: Write> DataGen<'a, T> {
 
     fn search(&mut self, board: Board, keystack: &mut Vec<u64>, node_limit: bool) -> Option<(Move, i16)> {
         let start = Instant::now();
-        let stop_after = start + Duration::from_secs_f32(if node_limit { 0.25 } else { 2.0 });
-        let mut s = search::Search::new(start, Some(stop_after), &self.tt, &mut self.history, &mut self.corrhist, &self.params);
-        let mut pv = ArrayVec::new();
+        let stop_after = (!node_limit).then(|| start + Duration::from_secs_f32(2.0));
+        let (soft_nodes, hard_nodes) = if node_limit { (Some(self.soft_nodes), Some(self.hard_nodes)) } else { (None, None) };
+        let shared = search::SharedSearch::default();
+        self.tt_generation = self.tt_generation.wrapping_add(1);
+        let mut s = search::Search::new(
+            start,
+            stop_after,
+            &self.tt,
+            &self.eval_cache,
+            &mut self.history,
+            &mut self.corrhist,
+            &self.params,
+            &shared,
+        )
+        .with_tt_generation(self.tt_generation)
+        .with_node_limits(soft_nodes, hard_nodes);
+        let mut best = None;
         let mut score = 0;
         let mut lower_bound = 50;
         let mut upper_bound = 50;
-        for depth in 0..=63 {
+        'deepening: for depth in 0..search::MAX_PLY as i32 {
+            let mut pv = Vec::new();
             loop {
-                pv.set_len(0);
                 let lower_window = score - lower_bound;
                 let upper_window = score + upper_bound;
                 let mut output = output::NoOp;
-                score = s.search_root(&board, depth, lower_window, upper_window, &mut output, &mut pv, keystack);
+                score = s.search_root(&board, depth, lower_window, upper_window, &mut output, &mut pv, keystack, &[]);
+                // An interrupted iteration still has a move if the first iteration didn't finish.
+                if s.stopped() {
+                    if best.is_none() {
+                        best = pv.first().map(|&m| (m, score as i16));
+                    }
+                    break 'deepening;
+                }
                 if score <= lower_window {
                     lower_bound *= 2;
                     continue;
@@ -266,17 +305,15 @@ impl<'a, T: Write> DataGen<'a, T> {
                 }
                 break;
             }
-            if node_limit && (s.nodes() + s.qnodes()) > 5_000 {
+            best = pv.first().map(|&m| (m, score as i16));
+            if s.soft_node_limit_reached() {
                 break;
             }
             if !node_limit && depth == 10 {
                 break;
             }
         }
-        if pv.is_empty() {
-            return None;
-        }
-        Some((pv[0], score as i16))
+        best
     }
 
     fn play_game(&mut self) -> bool {
@@ -326,7 +363,7 @@ impl<'a, T: Write> DataGen<'a, T> {
             ViriFormat::new(yukari_board.clone())
         };
 
-        // Rollout: "soft 5k nodes" until game end.
+        // Rollout: node-limited searches until game end.
         loop {
             assert_eq!(cc_board_stack.len(), yukari_board_stack.len());
             assert_eq!(keystack.len(), yukari_board_stack.len());
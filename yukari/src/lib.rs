@@ -2,7 +2,12 @@
 
 pub mod datagen;
 pub mod engine;
+mod movepicker;
 pub mod output;
+pub mod quantise;
 mod search;
 
-pub use search::{allocate_tt, is_repetition_draw, Search, SearchParams, TtEntry};
+pub use search::{
+    allocate_eval_cache, allocate_tt, clear_tt, eval_with_corrhist, is_repetition_draw, load_tt, save_tt, EvalCacheEntry, History,
+    Search, SearchParams, SharedSearch, TtBucket, MATE_THRESHOLD, MATE_VALUE, MAX_PLY,
+};
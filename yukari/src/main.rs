@@ -1,6 +1,13 @@
 use std::{
-    io::{self, BufWriter},
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
     str::FromStr,
+    sync::{
+        atomic::fence,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, TryRecvError},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
@@ -8,13 +15,13 @@ use colored::Colorize;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use tinyvec::ArrayVec;
 use yukari::{
-    self, allocate_tt, datagen,
+    self, allocate_eval_cache, allocate_tt, clear_tt, datagen,
     engine::{TimeControl, TimeMode},
-    is_repetition_draw,
+    eval_with_corrhist, is_repetition_draw, load_tt,
     output::{self, Output},
-    Search, SearchParams, TtEntry,
+    quantise, save_tt, EvalCacheEntry, History, Search, SearchParams, SharedSearch, TtBucket, MAX_PLY,
 };
-use yukari_movegen::{Board, Colour, Move, Piece, Square};
+use yukari_movegen::{Board, Colour, Move, Piece, Square, BENCH_FENS, OUTPUT_BUCKETS};
 
 #[derive(Clone, Copy, Debug)]
 enum Mode {
@@ -24,7 +31,9 @@ enum Mode {
     /// In force mode we just update our internal state, not responding with a move.
     /// xboard itself seems to use this to relay past game moves to the engine
     Force, // TODO: Update doc comment
-           // TODO: Analyze mode also exists
+    /// In analyze mode we update our state like force mode, but think about the current position
+    /// without end whenever there is nothing else to do
+    Analyze,
 }
 
 #[derive(Clone, Copy)]
@@ -34,6 +43,183 @@ pub enum Protocol {
     Uci,
 }
 
+/// Search tables belonging to one helper thread.
+#[derive(Clone)]
+struct Helper {
+    history: History,
+    corrhist: [[i32; 16384]; 2],
+}
+
+impl Helper {
+    fn new() -> Self {
+        Self { history: History::new(), corrhist: [[0; 16384]; 2] }
+    }
+}
+
+/// Iteratively deepen on a helper thread until the main thread stops the search.
+/// Odd-numbered helpers start a ply deeper, so that the threads spread out over neighbouring depths.
+/// Each search considers the root moves the main thread is searching when it starts, or every move if that is empty.
+fn helper_search(s: &mut Search, board: &Board, keystack: &mut Vec<u64>, root_moves: &Mutex<Vec<Move>>, id: usize, max_depth: i32) {
+    let mut depth = 1 + (id % 2) as i32;
+    let mut score = 0;
+    let mut pv = Vec::new();
+    while depth <= max_depth {
+        let mut lower_bound = 50;
+        let mut upper_bound = 50;
+        loop {
+            let lower_window = score - lower_bound;
+            let upper_window = score + upper_bound;
+            let moves = root_moves.lock().unwrap().clone();
+            score = s.search_root(board, depth, lower_window, upper_window, &mut output::NoOp, &mut pv, keystack, &moves);
+            if s.stopped() {
+                return;
+            }
+            if score <= lower_window {
+                lower_bound *= 2;
+                continue;
+            }
+            if score >= upper_window {
+                upper_bound *= 2;
+                continue;
+            }
+            break;
+        }
+        depth += 1;
+    }
+}
+
+/// Flags shared between the main thread and the thread reading commands from stdin.
+#[derive(Default)]
+pub struct Interrupts {
+    /// Set when the running search should stop as soon as possible.
+    stop: AtomicBool,
+    /// Set while a search is (or is about to be) running.
+    searching: AtomicBool,
+    /// Set while thinking on the opponent's time; cleared on `ponderhit` or when the predicted move arrives.
+    pondering: AtomicBool,
+    /// The move xboard pondering predicts the opponent will play.
+    ponder_move: Mutex<Option<String>>,
+    /// Set, before `stop`, when the opponent didn't play the move xboard pondering predicted.
+    ponder_miss: AtomicBool,
+    /// Set while analysing, when any command interrupts the search so it can be handled.
+    analysing: AtomicBool,
+    /// Set when xboard asks for a status update on the search with `.`.
+    status: AtomicBool,
+}
+
+/// Read commands from stdin and forward them to the main thread in order.
+/// Commands that must be acted on while a search is running are also handled here, since the main thread is busy.
+fn read_stdin(tx: &mpsc::Sender<String>, interrupts: &Interrupts) -> io::Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if io::stdin().read_line(&mut line)? == 0 {
+            // Nobody is left to stop a search.
+            interrupts.stop.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
+        let trimmed = line.trim();
+        let cmd = trimmed.split_once(' ').map_or(trimmed, |(cmd, _)| cmd);
+
+        // xboard has no ponderhit; we have to recognise the predicted move ourselves.
+        let ponder_move = interrupts.ponder_move.lock().unwrap().clone();
+        if let Some(ponder_move) = ponder_move.filter(|_| interrupts.pondering.load(Ordering::Relaxed)) {
+            match cmd {
+                _ if trimmed == ponder_move => {
+                    interrupts.pondering.store(false, Ordering::Relaxed);
+                    continue;
+                }
+                "ping" => {
+                    println!("pong {}", trimmed.split_once(' ').map_or("", |(_, args)| args));
+                    continue;
+                }
+                "time" | "otim" | "post" | "nopost" | "hard" | "computer" | "name" | "rating" | "accepted" | "rejected" => {}
+                // Anything else invalidates the prediction. Whoever sees the stop must also see why.
+                _ => {
+                    interrupts.ponder_miss.store(true, Ordering::SeqCst);
+                    interrupts.stop.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+
+        match cmd {
+            // The main thread clears this once it sees the command, so a stop that arrives before the search starts still stops it.
+            "stop" | "?" | "quit" => interrupts.stop.store(true, Ordering::Relaxed),
+            "isready" if interrupts.searching.load(Ordering::Relaxed) => {
+                println!("readyok");
+                continue;
+            }
+            "go" => {
+                interrupts.searching.store(true, Ordering::Relaxed);
+                if trimmed.split(' ').any(|word| word == "ponder") {
+                    interrupts.pondering.store(true, Ordering::Relaxed);
+                }
+            }
+            "ponderhit" => {
+                interrupts.pondering.store(false, Ordering::Relaxed);
+                continue;
+            }
+            "." => {
+                if interrupts.searching.load(Ordering::Relaxed) {
+                    interrupts.status.store(true, Ordering::Relaxed);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if tx.send(trimmed.to_string()).is_err() {
+            return Ok(());
+        }
+        // Either the main thread sees this command before it starts analysing, or we see that it is analysing.
+        fence(Ordering::SeqCst);
+        if interrupts.analysing.load(Ordering::Relaxed) {
+            interrupts.stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Settings that last for the whole session, unlike the game state in [`Yukari`].
+pub struct Options {
+    threads: usize,
+    multipv: usize,
+    /// Whether xboard wants us to think on the opponent's time.
+    ponder: bool,
+    /// Where the UCI `Save Hash` and `Load Hash` buttons save and load the TT.
+    hash_file: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { threads: 1, multipv: 1, ponder: false, hash_file: String::from("yukari.hash") }
+    }
+}
+
+/// Save the TT to the file at `path`, reporting any failure.
+fn save_hash(tt: &[TtBucket], path: &str, protocol: Protocol) {
+    let result = File::create(path).and_then(|f| {
+        let mut f = BufWriter::new(f);
+        save_tt(tt, &mut f)?;
+        f.flush()
+    });
+    if let Err(err) = result {
+        match protocol {
+            Protocol::Uci => println!("info string could not save hash to {path}: {err}"),
+            Protocol::Human | Protocol::Xboard => println!("# could not save hash to {path}: {err}"),
+        }
+    }
+}
+
+/// Load the TT from the file at `path`, reporting any failure.
+fn load_hash(tt: &[TtBucket], path: &str, protocol: Protocol) {
+    let result = File::open(path).and_then(|f| load_tt(tt, &mut BufReader::new(f)));
+    if let Err(err) = result {
+        match protocol {
+            Protocol::Uci => println!("info string could not load hash from {path}: {err}"),
+            Protocol::Human | Protocol::Xboard => println!("# could not load hash from {path}: {err}"),
+        }
+    }
+}
+
 /// The main engine state
 #[derive(Clone)]
 pub struct Yukari {
@@ -41,10 +227,24 @@ pub struct Yukari {
     tc: TimeControl,
     max_depth: Option<i32>,
     nodes_per_second: Option<u32>,
+    /// Stop searching after this many nodes, from UCI `go nodes`.
+    nodes: Option<u64>,
+    /// Whether UCI `go` gave a clock, which then still applies alongside a node limit.
+    timed: bool,
+    infinite: bool,
+    /// Search only for a forced mate in this many moves, from UCI `go mate`.
+    mate: Option<i32>,
     mode: Mode,
     keystack: Vec<u64>,
-    history: [[i16; 64]; 64],
+    /// The positions before each move made, for `undo`.
+    board_stack: Vec<Board>,
+    /// Root moves the search should not consider, from UCI `searchmoves` or xboard `exclude`.
+    excluded: Vec<Move>,
+    history: History,
     corrhist: [[i32; 16384]; 2],
+    helpers: Vec<Helper>,
+    /// Counts searches, so the TT can tell entries from earlier ones.
+    tt_generation: u8,
     params: SearchParams,
 }
 
@@ -59,11 +259,19 @@ impl Yukari {
             tc: TimeControl::new(TimeMode::MoveTime(5000)),
             max_depth: None,
             nodes_per_second: None,
+            nodes: None,
+            timed: false,
+            infinite: false,
+            mate: None,
             // Normal move making is on by default
             mode: Mode::Normal,
             keystack: Vec::new(),
-            history: [[0; 64]; 64],
+            board_stack: Vec::new(),
+            excluded: Vec::new(),
+            history: History::new(),
             corrhist: [[0; 16384]; 2],
+            helpers: Vec::new(),
+            tt_generation: 0,
             params: SearchParams::default(),
         }
     }
@@ -74,6 +282,28 @@ impl Yukari {
     pub fn set_board(&mut self, s: &str) {
         self.board = Board::from_fen(s).unwrap();
         self.keystack.clear();
+        self.board_stack.clear();
+        self.excluded.clear();
+    }
+
+    /// Play a move in the game, remembering the previous position for `undo`.
+    fn make_move(&mut self, m: Move) {
+        self.excluded.clear();
+        self.board_stack.push(self.board.clone());
+        self.board = self.board.make(m);
+        if is_repetition_draw(&self.keystack, self.board.hash()) {
+            println!("1/2-1/2 {{Draw by repetition}}");
+        }
+        self.keystack.push(self.board.hash());
+    }
+
+    /// Take back the last move played, if there is one.
+    fn undo(&mut self) {
+        if let Some(board) = self.board_stack.pop() {
+            self.excluded.clear();
+            self.board = board;
+            self.keystack.pop();
+        }
     }
 
     /// Parses the two xboard time control setup commands and sets that as our controls
@@ -110,207 +340,419 @@ impl Yukari {
         moves.into_iter().find(|&m| m.from == from && m.dest == dest && m.prom == prom)
     }
 
+    /// Parses a move in coordinate notation, if it is legal in the current position
+    #[must_use]
+    pub fn parse_move(&self, s: &str) -> Option<Move> {
+        let from = Square::from_str(s.get(..2)?).ok()?;
+        let dest = Square::from_str(s.get(2..4)?).ok()?;
+        let prom = match s.as_bytes().get(4) {
+            Some(b'n') => Some(Piece::Knight),
+            Some(b'b') => Some(Piece::Bishop),
+            Some(b'r') => Some(Piece::Rook),
+            Some(b'q') => Some(Piece::Queen),
+            _ => None,
+        };
+        self.find_move(from, dest, prom)
+    }
+
     /// Real search, falls back to dumb search in extreme time constraints
-    pub fn search(&mut self, best_pv: &mut ArrayVec<[Move; 64]>, tt: &mut [TtEntry], protocol: Protocol) {
-        let start = Instant::now();
-        let (soft_limit, hard_limit) = self.tc.search_time();
-        let mut soft_limit = start + Duration::from_secs_f32(soft_limit);
-        let hard_limit = start + Duration::from_secs_f32(hard_limit);
+    pub fn search(
+        &mut self, best_pv: &mut Vec<Move>, tt: &[TtBucket], eval_cache: &[EvalCacheEntry], options: &Options,
+        interrupts: &Interrupts, protocol: Protocol,
+    ) {
+        interrupts.searching.store(true, Ordering::Relaxed);
+        let mut start = Instant::now();
+        let (soft_secs, hard_secs) = self.tc.search_time();
+        let mut soft_limit = start + Duration::from_secs_f32(soft_secs);
+        let mut hard_limit = start + Duration::from_secs_f32(hard_secs);
 
         // while I love xboard protocol for its ease of parsing, the way fixed-nodes searching is implemented is *bad*.
-        let (nodes, stop_after) = if let Some(nodes_per_second) = self.nodes_per_second {
-            let TimeMode::MoveTime(movetime) = self.tc.mode else {
-                panic!("nps is only supported in st mode");
-            };
-            (Some(movetime * nodes_per_second / 1000), None)
+        // With nps set, nodes stand in for time: the time limits become node limits at that rate.
+        // A mate search runs until it proves or refutes the mate, unless told to stop.
+        let (soft_nodes, hard_nodes, stop_after) = if self.infinite || self.mate.is_some() {
+            (None, None, None)
+        } else if let Some(nodes) = self.nodes {
+            (None, Some(nodes), self.timed.then_some(hard_limit))
+        } else if let Some(nodes_per_second) = self.nodes_per_second {
+            let nodes_per_second = f64::from(nodes_per_second);
+            let soft_nodes = f64::from(soft_secs) * nodes_per_second;
+            let hard_nodes = f64::from(hard_secs) * nodes_per_second;
+            (Some(soft_nodes as u64), Some(hard_nodes as u64), None)
         } else {
-            (None, Some(hard_limit))
+            (None, None, Some(hard_limit))
         };
 
-        let mut s = Search::new(start, stop_after, tt, &mut self.history, &mut self.corrhist, &self.params);
-        // clone another to use inside the loop
-        // Use a seperate backing data to record the current move set
-        let mut depth = 1;
-        let mut score = 0;
-        let mut pv = ArrayVec::new();
-        let max_depth = self.max_depth.unwrap_or(63);
-        while depth <= max_depth {
-            println!("# time limits: soft {}s, hard {}s", (soft_limit - start).as_secs_f32(), (hard_limit - start).as_secs_f32());
-            let mut lower_bound = 50;
-            let mut upper_bound = 50;
-            loop {
-                pv.set_len(0);
-                let lower_window = score - lower_bound;
-                let upper_window = score + upper_bound;
-                let output: &mut dyn output::Output = match protocol {
-                    Protocol::Human => &mut output::Human::start(&self.board),
-                    Protocol::Xboard => &mut output::Xboard::start(&self.board),
-                    Protocol::Uci => &mut output::Uci::start(&self.board),
-                };
-                score = s.search_root(&self.board, depth, lower_window, upper_window, output, &mut pv, &mut self.keystack);
+        let mut max_depth = self.max_depth.unwrap_or(MAX_PLY as i32 - 1);
+        let shared = SharedSearch::new(&interrupts.stop, &interrupts.pondering);
+        self.tt_generation = self.tt_generation.wrapping_add(1);
+        let tt_generation = self.tt_generation;
+        self.helpers.resize_with(options.threads - 1, Helper::new);
+
+        let mut legal_moves = ArrayVec::new();
+        self.board.generate(&mut legal_moves);
+        // Excluding every move would leave nothing to play, so that means no restriction at all.
+        let mut allowed_moves = legal_moves.to_vec();
+        allowed_moves.retain(|m| !self.excluded.contains(m));
+        if allowed_moves.is_empty() {
+            allowed_moves = legal_moves.to_vec();
+        }
+        let restricted = allowed_moves.len() < legal_moves.len();
+        // The root moves of the line the main thread is searching, for the helpers to search too.
+        let shared_root_moves = Mutex::new(if restricted { allowed_moves.clone() } else { Vec::new() });
+
+        std::thread::scope(|scope| {
+            // The mate search is single-threaded.
+            let helpers = if self.mate.is_some() { &mut [][..] } else { &mut self.helpers[..] };
+            for (id, helper) in helpers.iter_mut().enumerate() {
+                let (board, mut keystack, params, shared) = (&self.board, self.keystack.clone(), &self.params, &shared);
+                let shared_root_moves = &shared_root_moves;
+                std::thread::Builder::new()
+                    .stack_size(8 * 1024 * 1024)
+                    .spawn_scoped(scope, move || {
+                        let mut s =
+                            Search::new(start, None, tt, eval_cache, &mut helper.history, &mut helper.corrhist, params, shared)
+                                .with_tt_generation(tt_generation);
+                        helper_search(&mut s, board, &mut keystack, shared_root_moves, id + 1, max_depth);
+                    })
+                    .unwrap();
+            }
+
+            let mut s =
+                Search::new(start, stop_after, tt, eval_cache, &mut self.history, &mut self.corrhist, &self.params, &shared)
+                    .with_status_request(&interrupts.status)
+                    .with_tt_generation(tt_generation)
+                    .with_node_limits(soft_nodes, hard_nodes);
+            // clone another to use inside the loop
+            // Use a seperate backing data to record the current move set
+            let mut depth = 1;
+            let lines = options.multipv.min(allowed_moves.len()).max(1);
+            if let Some(moves) = self.mate {
+                // Look for the shortest mate first.
+                for n in 1..=moves.clamp(1, MAX_PLY as i32 / 2) {
+                    let mut mate_pv = Vec::new();
+                    if let Some(score) = s.search_mate(&self.board, n, &mut mate_pv) {
+                        let output: &mut dyn output::Output = match protocol {
+                            Protocol::Human => &mut output::Human::start(&self.board),
+                            Protocol::Xboard => &mut output::Xboard::start(&self.board),
+                            Protocol::Uci => &mut output::Uci::start(&self.board),
+                        };
+                        let time = Instant::now().duration_since(start);
+                        output.complete(&self.board, 2 * n - 1, 2 * n - 1, score, time, s.total_nodes(), &mate_pv, true, false);
+                        best_pv.clone_from(&mate_pv);
+                        break;
+                    }
+                    if s.stopped() {
+                        break;
+                    }
+                }
+                if best_pv.is_empty() {
+                    match protocol {
+                        Protocol::Uci => println!("info string no mate in {moves} found"),
+                        Protocol::Human | Protocol::Xboard => println!("# no mate in {moves} found"),
+                    }
+                    // We still need a move to play: search normally, about as deep as the mate search went.
+                    max_depth = max_depth.min(2 * moves);
+                    if s.stopped() {
+                        best_pv.extend(allowed_moves.first().copied());
+                    }
+                } else {
+                    max_depth = 0;
+                }
+            }
+            // Each line centres its aspiration window on its own score from the previous iteration.
+            let mut scores = vec![0; lines];
+            while depth <= max_depth {
+                println!(
+                    "# time limits: soft {}s, hard {}s",
+                    (soft_limit - start).as_secs_f32(),
+                    (hard_limit - start).as_secs_f32()
+                );
+                // The best move of the previous iteration, to see how stable it is.
+                let previous_best = best_pv.first().copied();
+                // Each line after the first searches every move the earlier lines didn't choose.
+                let mut root_moves = if restricted { allowed_moves.clone() } else { Vec::new() };
+                for (line, score) in scores.iter_mut().enumerate() {
+                    shared_root_moves.lock().unwrap().clone_from(&root_moves);
+                    let mut line_pv = Vec::new();
+                    let mut lower_bound = 50;
+                    let mut upper_bound = 50;
+                    loop {
+                        let lower_window = *score - lower_bound;
+                        let upper_window = *score + upper_bound;
+                        let output: &mut dyn output::Output = match protocol {
+                            Protocol::Human => &mut output::Human::start(&self.board),
+                            Protocol::Xboard => &mut output::Xboard::start(&self.board),
+                            Protocol::Uci => &mut output::Uci::start_line(&self.board, line + 1),
+                        };
+                        *score = s.search_root(
+                            &self.board,
+                            depth,
+                            lower_window,
+                            upper_window,
+                            output,
+                            &mut line_pv,
+                            &mut self.keystack,
+                            &root_moves,
+                        );
+                        // If we have bailed out stop the loop
+                        if s.stopped() {
+                            output.abort();
+                            // Even a partial search finds a move to play.
+                            if line == 0 && best_pv.is_empty() {
+                                best_pv.clone_from(&line_pv);
+                            }
+                            break;
+                        }
+                        if *score <= lower_window {
+                            lower_bound *= 2;
+                            output.complete(
+                                &self.board,
+                                depth,
+                                s.seldepth(),
+                                *score,
+                                Instant::now().duration_since(start),
+                                s.total_nodes(),
+                                &line_pv,
+                                false,
+                                false,
+                            );
+                            continue;
+                        }
+                        if *score >= upper_window {
+                            upper_bound *= 2;
+                            output.complete(
+                                &self.board,
+                                depth,
+                                s.seldepth(),
+                                *score,
+                                Instant::now().duration_since(start),
+                                s.total_nodes(),
+                                &line_pv,
+                                false,
+                                true,
+                            );
+                            continue;
+                        }
+                        output.complete(
+                            &self.board,
+                            depth,
+                            s.seldepth(),
+                            *score,
+                            Instant::now().duration_since(start),
+                            s.total_nodes(),
+                            &line_pv,
+                            true,
+                            false,
+                        );
+                        if line == 0 {
+                            output.hashfull(s.hashfull());
+                            // The best line stands even if a later line is cut short.
+                            best_pv.clone_from(&line_pv);
+                        }
+                        break;
+                    }
+                    if s.stopped() {
+                        break;
+                    }
+                    if root_moves.is_empty() {
+                        root_moves.clone_from(&allowed_moves);
+                    }
+                    if let Some(best) = line_pv.first() {
+                        root_moves.retain(|m| m != best);
+                    }
+                }
                 // If we have bailed out stop the loop
-                if stop_after.is_some() && Instant::now() >= hard_limit {
-                    output.abort();
+                if s.stopped() {
                     break;
                 }
-                if score <= lower_window {
-                    lower_bound *= 2;
-                    output.complete(
-                        &self.board,
-                        depth,
-                        score,
-                        Instant::now().duration_since(start),
-                        s.nodes() + s.qnodes(),
-                        &pv,
-                        false,
-                        false,
-                    );
-                    continue;
+                // A ponder hit restarts the clock.
+                soft_limit = s.start() + (soft_limit - start);
+                hard_limit = s.start() + (hard_limit - start);
+                start = s.start();
+                // Modify time to search based on best move stability.
+                if let (TimeMode::Incremental { base: _, increment: _ }, Some(previous_best)) = (self.tc.mode, previous_best) {
+                    if best_pv.first() == Some(&previous_best) {
+                        let soft_limit_diff = soft_limit - start;
+                        soft_limit = start + soft_limit_diff.mul_f64(0.95);
+                    } else {
+                        let soft_limit_diff = soft_limit - start;
+                        soft_limit = start + soft_limit_diff.mul_f64(1.05);
+                    }
                 }
-                if score >= upper_window {
-                    upper_bound *= 2;
-                    output.complete(
-                        &self.board,
-                        depth,
-                        score,
-                        Instant::now().duration_since(start),
-                        s.nodes() + s.qnodes(),
-                        &pv,
-                        false,
-                        true,
-                    );
-                    continue;
+
+                if stop_after.is_some() && Instant::now() >= soft_limit && !s.pondering() {
+                    break;
                 }
-                output.complete(
-                    &self.board,
-                    depth,
-                    score,
-                    Instant::now().duration_since(start),
-                    s.nodes() + s.qnodes(),
-                    &pv,
-                    true,
-                    false,
-                );
-                break;
+                if s.soft_node_limit_reached() {
+                    break;
+                }
+                depth += 1;
             }
-            // If we have bailed out stop the loop
-            if stop_after.is_some() && Instant::now() >= hard_limit {
-                break;
+            // An infinite search, or one on the opponent's time, only finishes when told to.
+            while (self.infinite || s.pondering()) && !s.stopped() {
+                std::thread::sleep(Duration::from_millis(1));
             }
-            // Modify time to search based on best move stability.
-            if matches!(self.tc.mode, TimeMode::Incremental { base: _, increment: _ }) && !pv.is_empty() && !best_pv.is_empty() {
-                if pv[0] == best_pv[0] {
-                    let soft_limit_diff = soft_limit - start;
-                    soft_limit = start + soft_limit_diff.mul_f64(0.95);
-                } else {
-                    let soft_limit_diff = soft_limit - start;
-                    soft_limit = start + soft_limit_diff.mul_f64(1.05);
-                }
+            println!("# Avg AB cutoff index: {:.3}", s.beta_cutoff_index());
+            println!("# Avg QS cutoff index: {:.3}", s.q_beta_cutoff_index());
+            println!("# NMP success: {:.3}%", s.nullmove_success());
+            println!("# Eval cache hits: {:.3}%", s.eval_cache_hits());
+            println!("# QS nodes: {} {:.3}%", s.qnodes(), (100 * s.qnodes()) as f64 / (s.nodes() as f64 + s.qnodes() as f64));
+            println!("# ZW AB nodes: {:.3}%", s.zw_nodes());
+            println!("# ZW QS nodes: {:.3}%", s.zw_qnodes());
+            println!("# Branching factor: {:.3}", ((s.nodes() + s.qnodes()) as f64).powf(1.0 / f64::from(depth)));
+            // Let the helper threads know we're done.
+            shared.stop();
+        });
+        interrupts.pondering.store(false, Ordering::Relaxed);
+        *interrupts.ponder_move.lock().unwrap() = None;
+        interrupts.searching.store(false, Ordering::Relaxed);
+    }
+
+    /// Play the move the search chose as an xboard engine, then, if pondering is enabled, think on the opponent's time
+    /// for as long as they keep playing the reply we predicted.
+    fn play_xboard(
+        &mut self, mut pv: Vec<Move>, tt: &[TtBucket], eval_cache: &[EvalCacheEntry], options: &Options, interrupts: &Interrupts,
+        protocol: Protocol,
+    ) {
+        let mut ponder = options.ponder;
+        while let Some(&m) = pv.first() {
+            // The prediction must be in place before the opponent can see our move.
+            let prediction = pv.get(1).copied().filter(|_| ponder);
+            if let Some(prediction) = prediction {
+                *interrupts.ponder_move.lock().unwrap() = Some(prediction.to_string());
+                interrupts.ponder_miss.store(false, Ordering::Relaxed);
+                interrupts.pondering.store(true, Ordering::Relaxed);
             }
 
-            // If we have a pv that's not just empty from bailing out use that as our best moves
-            best_pv.clone_from(&pv);
+            // We must actually make the move locally too
+            println!("move {m}");
+            self.make_move(m);
+            self.tc.increment_moves();
 
-            if stop_after.is_some() && Instant::now() >= soft_limit {
-                break;
-            }
-            if let Some(nodes) = nodes {
-                if s.nodes() + s.qnodes() >= nodes as u64 {
-                    break;
-                }
+            let Some(prediction) = prediction else {
+                return;
+            };
+            self.board_stack.push(self.board.clone());
+            self.board = self.board.make(prediction);
+            self.keystack.push(self.board.hash());
+            pv = Vec::new();
+            self.search(&mut pv, tt, eval_cache, options, interrupts, protocol);
+
+            if interrupts.stop.load(Ordering::SeqCst) && interrupts.ponder_miss.swap(false, Ordering::SeqCst) {
+                // They played something else; the command will be handled normally.
+                self.undo();
+                interrupts.stop.store(false, Ordering::Relaxed);
+                return;
             }
-            depth += 1;
+            // Any other stop came after they played the predicted move (a `?`, say), so play what the search found, but
+            // don't ponder again before the command that stopped it is handled.
+            ponder = !interrupts.stop.load(Ordering::Relaxed);
         }
-        println!("# Avg AB cutoff index: {:.3}", s.beta_cutoff_index());
-        println!("# Avg QS cutoff index: {:.3}", s.q_beta_cutoff_index());
-        println!("# NMP success: {:.3}%", s.nullmove_success());
-        println!("# QS nodes: {} {:.3}%", s.qnodes(), (100 * s.qnodes()) as f64 / (s.nodes() as f64 + s.qnodes() as f64));
-        println!("# ZW AB nodes: {:.3}%", s.zw_nodes());
-        println!("# ZW QS nodes: {:.3}%", s.zw_qnodes());
-        println!("# Branching factor: {:.3}", ((s.nodes() + s.qnodes()) as f64).powf(1.0 / f64::from(depth)));
     }
 
-    fn bench(&mut self, tt: &mut [TtEntry]) {
-        let fens = [
-            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
-            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 10",
-            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 11",
-            "4rrk1/pp1n3p/3q2pQ/2p1pb2/2PP4/2P3N1/P2B2PP/4RRK1 b - - 7 19",
-            "rq3rk1/ppp2ppp/1bnpN3/3N2B1/4P3/7P/PPPQ1PP1/2KR3R b - - 0 14",
-            "r1bq1r1k/1pp1n1pp/1p1p4/4p2Q/4PpP1/1BNP4/PPP2P1P/3R1RK1 b - g3 0 14",
-            "r3r1k1/2p2ppp/p1p1bn2/8/1q2P3/2NPQN2/PPP3PP/R4RK1 b - - 2 15",
-            "r1bbk1nr/pp3p1p/2n5/1N4p1/2Np1B2/8/PPP2PPP/2KR1B1R w kq - 0 13",
-            "r1bq1rk1/ppp1nppp/4n3/3p3Q/3P4/1BP1B3/PP1N2PP/R4RK1 w - - 1 16",
-            "4r1k1/r1q2ppp/ppp2n2/4P3/5Rb1/1N1BQ3/PPP3PP/R5K1 w - - 1 17",
-            "2rqkb1r/ppp2p2/2npb1p1/1N1Nn2p/2P1PP2/8/PP2B1PP/R1BQK2R b KQ - 0 11",
-            "r1bq1r1k/b1p1npp1/p2p3p/1p6/3PP3/1B2NN2/PP3PPP/R2Q1RK1 w - - 1 16",
-            "3r1rk1/p5pp/bpp1pp2/8/q1PP1P2/b3P3/P2NQRPP/1R2B1K1 b - - 6 22",
-            "r1q2rk1/2p1bppp/2Pp4/p6b/Q1PNp3/4B3/PP1R1PPP/2K4R w - - 2 18",
-            "4k2r/1pb2ppp/1p2p3/1R1p4/3P4/2r1PN2/P4PPP/1R4K1 b - - 3 22",
-            "3q2k1/pb3p1p/4pbp1/2r5/PpN2N2/1P2P2P/5PP1/Q2R2K1 b - - 4 26",
-            "6k1/6p1/6Pp/ppp5/3pn2P/1P3K2/1PP2P2/3N4 b - - 0 1",
-            "3b4/5kp1/1p1p1p1p/pP1PpP1P/P1P1P3/3KN3/8/8 w - - 0 1",
-            "2K5/p7/7P/5pR1/8/5k2/r7/8 w - - 0 1",
-            "8/6pk/1p6/8/PP3p1p/5P2/4KP1q/3Q4 w - - 0 1",
-            "7k/3p2pp/4q3/8/4Q3/5Kp1/P6b/8 w - - 0 1",
-            "8/2p5/8/2kPKp1p/2p4P/2P5/3P4/8 w - - 0 1",
-            "8/1p3pp1/7p/5P1P/2k3P1/8/2K2P2/8 w - - 0 1",
-            "8/pp2r1k1/2p1p3/3pP2p/1P1P1P1P/P5KR/8/8 w - - 0 1",
-            "8/3p4/p1bk3p/Pp6/1Kp1PpPp/2P2P1P/2P5/5B2 b - - 0 1",
-            "5k2/7R/4P2p/5K2/p1r2P1p/8/8/8 b - - 0 1",
-            "6k1/6p1/P6p/r1N5/5p2/7P/1b3PP1/4R1K1 w - - 0 1",
-            "1r3k2/4q3/2Pp3b/3Bp3/2Q2p2/1p1P2P1/1P2KP2/3N4 w - - 0 1",
-            "6k1/4pp1p/3p2p1/P1pPb3/R7/1r2P1PP/3B1P2/6K1 w - - 0 1",
-            "8/3p3B/5p2/5P2/p7/PP5b/k7/6K1 w - - 0 1",
-            "5rk1/q6p/2p3bR/1pPp1rP1/1P1Pp3/P3B1Q1/1K3P2/R7 w - - 93 90",
-            "4rrk1/1p1nq3/p7/2p1P1pp/3P2bp/3Q1Bn1/PPPB4/1K2R1NR w - - 40 21",
-            "r3k2r/3nnpbp/q2pp1p1/p7/Pp1PPPP1/4BNN1/1P5P/R2Q1RK1 w kq - 0 16",
-            "3Qb1k1/1r2ppb1/pN1n2q1/Pp1Pp1Pr/4P2p/4BP2/4B1R1/1R5K b - - 11 40",
-            "4k3/3q1r2/1N2r1b1/3ppN2/2nPP3/1B1R2n1/2R1Q3/3K4 w - - 5 1",
-            // 5-man positions
-            "8/8/8/8/5kp1/P7/8/1K1N4 w - - 0 1",  // Kc2 - mate
-            "8/8/8/5N2/8/p7/8/2NK3k w - - 0 1",   // Na2 - mate
-            "8/3k4/8/8/8/4B3/4KB2/2B5 w - - 0 1", // draw
-            // 6-man positions
-            "8/8/1P6/5pr1/8/4R3/7k/2K5 w - - 0 1",  // Re5 - mate
-            "8/2p4P/8/kr6/6R1/8/8/1K6 w - - 0 1",   // Ka2 - mate
-            "8/8/3P3k/8/1p6/8/1P6/1K3n2 b - - 0 1", // Nd2 - draw
-            // 7-man positions
-            "8/R7/2q5/8/6k1/8/1P5p/K6R w - - 0 124", // Draw
-            // Mate and stalemate positions
-            "6k1/3b3r/1p1p4/p1n2p2/1PPNpP1q/P3Q1p1/1R1RB1P1/5K2 b - - 0 1",
-            "r2r1n2/pp2bk2/2p1p2p/3q4/3PN1QP/2P3R1/P4PP1/5RK1 w - - 0 1",
-            "8/8/8/8/8/6k1/6p1/6K1 w - -",
-            "7k/7P/6K1/8/3B4/8/8/8 b - -",
-        ];
+    /// Think about the current position until a command arrives, and return it.
+    fn analyse(
+        &mut self, rx: &mpsc::Receiver<String>, tt: &[TtBucket], eval_cache: &[EvalCacheEntry], options: &Options,
+        interrupts: &Interrupts, protocol: Protocol,
+    ) -> Option<String> {
+        interrupts.stop.store(false, Ordering::Relaxed);
+        interrupts.analysing.store(true, Ordering::Relaxed);
+        // Either the reader thread sees that we're analysing, or we see the command it sent.
+        fence(Ordering::SeqCst);
+        let line = match rx.try_recv() {
+            Ok(line) => Some(line),
+            Err(TryRecvError::Empty) => {
+                let mut pv = Vec::new();
+                self.infinite = true;
+                self.search(&mut pv, tt, eval_cache, options, interrupts, protocol);
+                self.infinite = false;
+                rx.recv().ok()
+            }
+            Err(TryRecvError::Disconnected) => None,
+        };
+        interrupts.analysing.store(false, Ordering::Relaxed);
+        interrupts.stop.store(false, Ordering::Relaxed);
+        line
+    }
+
+    /// Print the network's view of the current position: raw output per bucket, the bucket actually used,
+    /// the correction-history adjusted score, and how much each piece contributes to the evaluation.
+    fn eval_trace(&self) {
+        let board = &self.board;
+        let side = board.side();
+        let bucket = board.output_bucket();
+        let eval = board.eval(side);
+
+        println!("{board}");
+        println!("bucket  output");
+        for i in 0..OUTPUT_BUCKETS {
+            let marker = if i == bucket { " <-" } else { "" };
+            println!("{i:>6}  {:>6}{marker}", board.eval_in_bucket(side, i));
+        }
+        println!("selected bucket: {bucket}");
+        println!("static eval: {eval}");
+        println!("corrected eval: {}", eval_with_corrhist(&self.corrhist, board, eval));
+        println!();
+
+        // Contribution of each piece, measured as the eval change when it is removed.
+        println!("piece contributions (side to move, centipawns):");
+        for i in 0_u8..64_u8 {
+            let j = i ^ 0o70_u8;
+            let square = Square::try_from(j).unwrap();
+
+            let square_colour = |s: &str| if (j & 1) ^ ((j >> 3) & 1) == 0 { s.on_green() } else { s.on_white() };
+
+            if let (Some(piece), Some(colour)) = (board.piece_from_square(square), board.data().colour_from_square(square)) {
+                let c = match piece {
+                    Piece::Pawn => '♙',
+                    Piece::Knight => '♘',
+                    Piece::Bishop => '♗',
+                    Piece::Rook => '♖',
+                    Piece::Queen => '♕',
+                    Piece::King => '♔',
+                };
+                let contribution = board
+                    .eval_without_piece(square, side)
+                    .map_or_else(|| "     ".to_string(), |without| format!("{:>+5}", eval - without));
+                let cell = format!(" {c} {contribution} ");
+                let cell = if colour == Colour::White { cell.bright_white() } else { cell.black() };
+                print!("{}", square_colour(&cell));
+            } else {
+                print!("{}", square_colour("         "));
+            }
+
+            if j & 7 == 7 {
+                println!();
+            }
+        }
+    }
 
+    fn bench(&mut self, tt: &mut [TtBucket], eval_cache: &[EvalCacheEntry]) {
         let mut nodes = 0;
         let start = Instant::now();
-        for fen in fens {
+        for (generation, fen) in BENCH_FENS.iter().enumerate() {
             let board = Board::from_fen(fen).unwrap();
             let start = Instant::now();
-            for from in 0..64 {
-                for dest in 0..64 {
-                    self.history[from][dest] = 0;
-                }
-            }
-            let mut s = Search::new(start, None, tt, &mut self.history, &mut self.corrhist, &self.params);
+            self.history = History::new();
+            let shared = SharedSearch::default();
+            let mut s = Search::new(start, None, tt, eval_cache, &mut self.history, &mut self.corrhist, &self.params, &shared)
+                .with_tt_generation(generation as u8);
             let mut keystack = Vec::new();
-            let mut pv = ArrayVec::new();
+            let mut pv = Vec::new();
             let mut score = 0;
             let mut lower_bound = 50;
             let mut upper_bound = 50;
             loop {
-                pv.set_len(0);
                 let lower_window = score - lower_bound;
                 let upper_window = score + upper_bound;
-                let mut output = output::Xboard::start(&self.board);
-                score = s.search_root(&board, 11, lower_window, upper_window, &mut output, &mut pv, &mut keystack);
+                let mut output = output::Xboard::start(&board);
+                score = s.search_root(&board, 11, lower_window, upper_window, &mut output, &mut pv, &mut keystack, &[]);
                 if score <= lower_window {
                     lower_bound *= 2;
                     output.complete(
                         &board,
                         11,
+                        s.seldepth(),
                         score,
                         Instant::now().duration_since(start),
                         s.nodes() + s.qnodes(),
@@ -325,6 +767,7 @@ impl Yukari {
                     output.complete(
                         &board,
                         11,
+                        s.seldepth(),
                         score,
                         Instant::now().duration_since(start),
                         s.nodes() + s.qnodes(),
@@ -334,7 +777,17 @@ impl Yukari {
                     );
                     continue;
                 }
-                output.complete(&board, 11, score, Instant::now().duration_since(start), s.nodes() + s.qnodes(), &pv, true, false);
+                output.complete(
+                    &board,
+                    11,
+                    s.seldepth(),
+                    score,
+                    Instant::now().duration_since(start),
+                    s.nodes() + s.qnodes(),
+                    &pv,
+                    true,
+                    false,
+                );
                 break;
             }
             nodes += s.nodes() + s.qnodes();
@@ -391,23 +844,55 @@ const YUKARI: &str = "
 fn main() -> io::Result<()> {
     let mut engine = Yukari::new();
     let mut tt = allocate_tt(16);
+    let eval_cache = allocate_eval_cache(2);
+    let mut options = Options::default();
     let mut protocol = Protocol::Human;
 
-    for arg in std::env::args() {
+    let args = std::env::args().collect::<Vec<_>>();
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "quantise" {
+            // quantise <float weights> <output network> [QA] [QB]
+            let (Some(input), Some(output)) = (args.get(i + 1), args.get(i + 2)) else {
+                println!("usage: yukari quantise <float weights> <output network> [QA] [QB]");
+                return Ok(());
+            };
+            let qa = args.get(i + 3).map_or(yukari_movegen::QA, |qa| qa.parse().unwrap());
+            let qb = args.get(i + 4).map_or(yukari_movegen::QB, |qb| qb.parse().unwrap());
+
+            let float = quantise::FloatNetwork::from_bytes(&std::fs::read(input)?)?;
+            let quantised = float.quantise(qa, qb)?;
+            quantised.write(&mut BufWriter::new(std::fs::File::create(output)?))?;
+            println!("wrote {output} with QA={qa} QB={qb}");
+
+            if qa != yukari_movegen::QA || qb != yukari_movegen::QB {
+                println!("warning: the engine is built with QA={} QB={}", yukari_movegen::QA, yukari_movegen::QB);
+            }
+            let overflows = quantised.overflowing_output_weights();
+            if overflows > 0 {
+                println!("warning: {overflows} output weights overflow i16 when multiplied by QA");
+            }
+
+            let positions = quantise::sample_positions(10_000);
+            let (max, mean) = quantise::compare(&float, &quantised, &positions);
+            println!("{} positions: max error {max:.2}cp, mean error {mean:.2}cp", positions.len());
+            return Ok(());
+        }
         if arg == "bench" {
-            engine.bench(&mut tt);
+            engine.bench(&mut tt, &eval_cache);
             return Ok(());
         }
         if arg == "datagen" {
             const GAMES: usize = 500_000;
             const BATCH: usize = 1_000;
+            const SOFT_NODES: u64 = 5_000;
+            const HARD_NODES: u64 = 100_000;
 
             let positions = (0..(GAMES / BATCH))
                 .into_par_iter()
                 .map(|id| {
                     let f = std::fs::File::options().create(true).append(true).open(format!("games{id}.viriformat")).unwrap();
                     let mut f = BufWriter::new(f);
-                    let mut dg = datagen::DataGen::new(&mut f);
+                    let mut dg = datagen::DataGen::new(&mut f).with_node_limits(SOFT_NODES, HARD_NODES);
                     dg.play(BATCH)
                 })
                 .sum::<usize>();
@@ -420,15 +905,24 @@ fn main() -> io::Result<()> {
 
     println!("{}", YUKARI.purple());
 
-    let mut line = String::new();
+    let interrupts = Arc::new(Interrupts::default());
+    let (tx, rx) = mpsc::channel();
+    {
+        let interrupts = Arc::clone(&interrupts);
+        std::thread::spawn(move || read_stdin(&tx, &interrupts));
+    }
+
     loop {
-        line.clear();
-        let count = io::stdin().read_line(&mut line)?;
-        if count == 0 {
-            println!("# got zero read");
-            continue;
-        }
-        let trimmed = line.trim();
+        let line = if matches!(engine.mode, Mode::Analyze) {
+            engine.analyse(&rx, &tt, &eval_cache, &options, &interrupts, protocol)
+        } else {
+            rx.recv().ok()
+        };
+        // The reader thread hangs up when stdin is closed.
+        let Some(line) = line else {
+            break;
+        };
+        let trimmed = line.as_str();
         let (mut cmd, mut args) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
 
         #[allow(clippy::match_same_arms)]
@@ -442,7 +936,13 @@ fn main() -> io::Result<()> {
                 println!("id name Yukari 2025.2.4");
                 println!("id author Hannah Ravensloft");
                 println!("option name Hash type spin default 16 min 1 max 8192");
-                println!("option name Threads type spin default 1 min 1 max 1");
+                println!("option name Threads type spin default 1 min 1 max 256");
+                println!("option name Ponder type check default false");
+                println!("option name MultiPV type spin default 1 min 1 max 256");
+                println!("option name Clear Hash type button");
+                println!("option name HashFile type string default yukari.hash");
+                println!("option name Save Hash type button");
+                println!("option name Load Hash type button");
                 println!("uciok");
             }
             // This is where we send our features
@@ -463,6 +963,8 @@ fn main() -> io::Result<()> {
                 println!("feature memory=1");
                 // We support nps for fixed-nodes search.
                 println!("feature nps=1");
+                // We can search with multiple threads.
+                println!("feature smp=1");
                 // Tunables!
                 /*
                 println!("feature option=\"RfpMarginBase -spin 0 0 100\"");
@@ -473,9 +975,24 @@ fn main() -> io::Result<()> {
                 println!("feature option=\"HistBonusMul -spin 300 0 600\"");
                 println!("feature option=\"HistPenaltyBase -spin 250 0 500\"");
                 println!("feature option=\"HistPenaltyMul -spin 300 0 600\"");
+                println!("feature option=\"SeePruneDepth -spin 6 0 20\"");
+                println!("feature option=\"SeeCaptureMargin -spin 30 0 200\"");
+                println!("feature option=\"SeeQuietMargin -spin 60 0 400\"");
+                println!("feature option=\"DeltaMargin -spin 200 0 1000\"");
+                println!("feature option=\"SingularDepth -spin 8 0 20\"");
+                println!("feature option=\"SingularMargin -spin 2 0 20\"");
+                println!("feature option=\"DoubleExtensionMargin -spin 20 0 200\"");
+                println!("feature option=\"MaxDoubleExtensions -spin 6 0 20\"");
+                println!("feature option=\"KillerBonus -spin 32768 -65536 65536\"");
+                println!("feature option=\"CounterBonus -spin 8192 -65536 65536\"");
+                println!("feature option=\"QsearchChecks -check 0\"");
                 */
                 println!("feature option=\"Hash -spin 16 1 8192\"");
-                println!("feature option=\"Threads -spin 1 1 1\"");
+                println!("feature option=\"Threads -spin 1 1 256\"");
+                println!("feature option=\"MultiPV -spin 1 1 256\"");
+                println!("feature option=\"Clear Hash -button\"");
+                // We can restrict the moves searched in analysis.
+                println!("feature exclude=1");
                 // Communicate that feature reporting is done
                 println!("feature done=1");
             }
@@ -537,7 +1054,10 @@ fn main() -> io::Result<()> {
                 }
             }
             // Reset the entire state of the engine
-            "new" | "ucinewgame" => engine = Yukari::new(),
+            "new" | "ucinewgame" => {
+                engine = Yukari::new();
+                clear_tt(&tt);
+            }
             // Parse our two time controls from the whole commmand lines
             // TODO: This is rather xboard specific
             "level" => engine.parse_tc(trimmed),
@@ -548,6 +1068,12 @@ fn main() -> io::Result<()> {
                 let megabytes = args.parse::<usize>().unwrap();
                 tt = allocate_tt(megabytes);
             }
+            // Set the number of search threads.
+            "cores" => options.threads = args.parse::<usize>().unwrap().clamp(1, 256),
+            "option" if args == "Clear Hash" => clear_tt(&tt),
+            // Save or load the TT, keeping the current size.
+            "savehash" => save_hash(&tt, args, protocol),
+            "loadhash" => load_hash(&tt, args, protocol),
             "option" => {
                 let (name, value) = args.split_once("=").unwrap();
                 let value = value.parse::<i32>().unwrap();
@@ -560,11 +1086,26 @@ fn main() -> io::Result<()> {
                     "HistBonusMul" => engine.params.hist_bonus_mul = value,
                     "HistPenaltyBase" => engine.params.hist_pen_base = value,
                     "HistPenaltyMul" => engine.params.hist_pen_mul = value,
+                    "SeePruneDepth" => engine.params.see_prune_depth = value,
+                    "SeeCaptureMargin" => engine.params.see_capture_margin = value,
+                    "SeeQuietMargin" => engine.params.see_quiet_margin = value,
+                    "DeltaMargin" => engine.params.delta_margin = value,
+                    "SingularDepth" => engine.params.singular_depth = value,
+                    "SingularMargin" => engine.params.singular_margin = value,
+                    "DoubleExtensionMargin" => engine.params.double_extension_margin = value,
+                    "MaxDoubleExtensions" => engine.params.max_double_extensions = value,
+                    "KillerBonus" => engine.params.killer_bonus = value,
+                    "CounterBonus" => engine.params.counter_bonus = value,
+                    "QsearchChecks" => engine.params.qsearch_checks = value != 0,
                     "Hash" if value >= 1 => tt = allocate_tt(value as usize), // UCIism. grumble grumble.
-                    "Threads" => (),                                          // UCIism, grumble grumble.
+                    "Threads" => options.threads = value.clamp(1, 256) as usize,
+                    "MultiPV" => options.multipv = value.clamp(1, 256) as usize,
                     _ => (),
                 }
             }
+            "setoption" if args == "name Clear Hash" => clear_tt(&tt),
+            "setoption" if args == "name Save Hash" => save_hash(&tt, &options.hash_file, protocol),
+            "setoption" if args == "name Load Hash" => load_hash(&tt, &options.hash_file, protocol),
             "setoption" => {
                 let (name, args) = args.split_once(" ").unwrap_or((args, ""));
                 assert_eq!(name, "name");
@@ -572,6 +1113,15 @@ fn main() -> io::Result<()> {
                 let (value, args) = args.split_once(" ").unwrap_or((args, ""));
                 assert_eq!(value, "value");
                 let (value, _) = args.split_once(" ").unwrap_or((args, ""));
+                // We ponder whenever the GUI asks us to, so there is nothing to set.
+                if name == "Ponder" {
+                    continue;
+                }
+                // The path is the whole rest of the line, spaces and all.
+                if name == "HashFile" {
+                    options.hash_file = args.to_string();
+                    continue;
+                }
                 let value = value.parse::<i32>().unwrap();
                 match name {
                     "RfpMarginBase" => engine.params.rfp_margin_base = value,
@@ -582,15 +1132,30 @@ fn main() -> io::Result<()> {
                     "HistBonusMul" => engine.params.hist_bonus_mul = value,
                     "HistPenaltyBase" => engine.params.hist_pen_base = value,
                     "HistPenaltyMul" => engine.params.hist_pen_mul = value,
+                    "SeePruneDepth" => engine.params.see_prune_depth = value,
+                    "SeeCaptureMargin" => engine.params.see_capture_margin = value,
+                    "SeeQuietMargin" => engine.params.see_quiet_margin = value,
+                    "DeltaMargin" => engine.params.delta_margin = value,
+                    "SingularDepth" => engine.params.singular_depth = value,
+                    "SingularMargin" => engine.params.singular_margin = value,
+                    "DoubleExtensionMargin" => engine.params.double_extension_margin = value,
+                    "MaxDoubleExtensions" => engine.params.max_double_extensions = value,
+                    "KillerBonus" => engine.params.killer_bonus = value,
+                    "CounterBonus" => engine.params.counter_bonus = value,
+                    "QsearchChecks" => engine.params.qsearch_checks = value != 0,
                     "Hash" if value >= 1 => tt = allocate_tt(value as usize), // UCIism. grumble grumble.
-                    "Threads" => (),                                          // UCIism, grumble grumble.
+                    "Threads" => options.threads = value.clamp(1, 256) as usize,
+                    "MultiPV" => options.multipv = value.clamp(1, 256) as usize,
                     _ => (),
                 }
             }
             "eval" => println!("{}", engine.board.eval(engine.board.side())),
-            // Hard would turn on thinking during opponent's time, easy would turn it off
-            // we don't do it, so it's unimportant
-            "hard" | "easy" => {}
+            "evaltrace" => engine.eval_trace(),
+            // Hard turns on thinking during the opponent's time, easy turns it off
+            "hard" => options.ponder = true,
+            "easy" => options.ponder = false,
+            // The reader thread has already acted on this.
+            "ponderhit" => {}
             "quit" => {
                 break;
             }
@@ -598,11 +1163,13 @@ fn main() -> io::Result<()> {
             // TODO: Handle rejects we can't tolerate and abort early
             "accepted" | "rejected" => {}
             // Ping expects a response with the correct tag once the commands prior to the ping are done
-            // That ends up being some GPU fence level synchronization nonsense if it were to send more than one
-            // so for now we just "handle it" by replying with pong immediately. For now this "works" because
-            // the engine is single threaded such that moves can never be passed by other commands
+            // Commands are read on their own thread but handled strictly in order here, and searches run to
+            // completion before the next command is looked at, so replying immediately is correct.
             "ping" => println!("pong {args}"),
+            // During a search the reader thread answers this itself.
             "isready" => println!("readyok"),
+            // The reader thread has already acted on these; clear the flag for the next search.
+            "stop" | "?" => interrupts.stop.store(false, Ordering::Relaxed),
             // TODO: Should support randomization so we don't always play the same game
             // we can't todo!() because we cannot turn off getting this message
             "random" => {}
@@ -616,6 +1183,11 @@ fn main() -> io::Result<()> {
             "nps" => engine.set_nodes_per_second(i32::from_str(args).unwrap()),
             "go" => {
                 let mut uci = false;
+                engine.infinite = false;
+                engine.mate = None;
+                engine.nodes = None;
+                engine.timed = false;
+                let mut searchmoves = Vec::new();
                 // is this a UCI go?
                 while !args.is_empty() {
                     uci = true;
@@ -624,12 +1196,14 @@ fn main() -> io::Result<()> {
                         "wtime" => {
                             (cmd, args) = args.split_once(" ").unwrap_or((args, ""));
                             if engine.board.side() == Colour::White {
+                                engine.timed = true;
                                 engine.set_remaining((u32::from_str(cmd).unwrap() / 10) as f32);
                             }
                         }
                         "btime" => {
                             (cmd, args) = args.split_once(" ").unwrap_or((args, ""));
                             if engine.board.side() == Colour::Black {
+                                engine.timed = true;
                                 engine.set_remaining((u32::from_str(cmd).unwrap() / 10) as f32);
                             }
                         }
@@ -647,6 +1221,7 @@ fn main() -> io::Result<()> {
                         }
                         "movetime" => {
                             (cmd, args) = args.split_once(" ").unwrap_or((args, ""));
+                            engine.timed = true;
                             engine.tc.mode.fixed_time_per_move((u32::from_str(cmd).unwrap() as f32) / 1000.0);
                         }
                         "depth" => {
@@ -655,37 +1230,69 @@ fn main() -> io::Result<()> {
                         }
                         "nodes" => {
                             (cmd, args) = args.split_once(" ").unwrap_or((args, ""));
-                            engine.nodes_per_second = Some(u32::from_str(cmd).unwrap());
-                            engine.tc.mode.fixed_time_per_move(1.0);
+                            engine.nodes = Some(u64::from_str(cmd).unwrap());
                         }
                         "mate" => {
-                            (_, args) = args.split_once(" ").unwrap_or((args, ""));
+                            (cmd, args) = args.split_once(" ").unwrap_or((args, ""));
+                            engine.mate = Some(i32::from_str(cmd).unwrap());
                         }
-                        "infinite" | "ponder" => {}
+                        "searchmoves" => {
+                            // This takes every following argument that is a legal move.
+                            while let Some(m) = engine.parse_move(args.split_once(" ").map_or(args, |(m, _)| m)) {
+                                searchmoves.push(m);
+                                (_, args) = args.split_once(" ").unwrap_or((args, ""));
+                            }
+                        }
+                        "infinite" => engine.infinite = true,
+                        "ponder" => {}
                         _ => {} // ignore anything we don't understand.
                     }
                 }
+                if uci {
+                    let mut moves = ArrayVec::new();
+                    engine.board.generate(&mut moves);
+                    engine.excluded = moves.into_iter().filter(|m| !searchmoves.is_empty() && !searchmoves.contains(m)).collect();
+                }
                 engine.mode = Mode::Normal;
                 // When we get go we should make a move immediately
-                let mut pv = ArrayVec::new();
-                engine.search(&mut pv, &mut tt, protocol);
+                let mut pv = Vec::new();
+                engine.search(&mut pv, &tt, &eval_cache, &options, &interrupts, protocol);
                 // Choose the top move
                 let m = pv[0];
                 if uci {
-                    println!("bestmove {m}");
+                    if let Some(ponder_move) = pv.get(1) {
+                        println!("bestmove {m} ponder {ponder_move}");
+                    } else {
+                        println!("bestmove {m}");
+                    }
                     engine.mode = Mode::Force;
                 } else {
-                    // We must actually make the move locally too
-                    engine.board = engine.board.make(m);
-                    println!("move {m}");
-                    if is_repetition_draw(&engine.keystack, engine.board.hash()) {
-                        println!("1/2-1/2 {{Draw by repetition}}");
-                    }
-                    engine.keystack.push(engine.board.hash());
-                    engine.tc.increment_moves();
+                    engine.play_xboard(pv, &tt, &eval_cache, &options, &interrupts, protocol);
                 }
             }
             "force" => engine.mode = Mode::Force,
+            "analyze" => engine.mode = Mode::Analyze,
+            "exit" => engine.mode = Mode::Force,
+            "undo" => engine.undo(),
+            // Restrict the moves considered at the root of the search
+            "exclude" if args == "all" => {
+                let mut moves = ArrayVec::new();
+                engine.board.generate(&mut moves);
+                engine.excluded = moves.to_vec();
+            }
+            "include" if args == "all" => engine.excluded.clear(),
+            "exclude" => {
+                if let Some(m) = engine.parse_move(args) {
+                    if !engine.excluded.contains(&m) {
+                        engine.excluded.push(m);
+                    }
+                }
+            }
+            "include" => {
+                if let Some(m) = engine.parse_move(args) {
+                    engine.excluded.retain(|&excluded| excluded != m);
+                }
+            }
             "d" => println!("{}", engine.board),
             _ => {
                 // Always ascii
@@ -709,33 +1316,16 @@ fn main() -> io::Result<()> {
                         Mode::Normal => {
                             // Find the move in the list
                             let m = engine.find_move(from, dest, prom).expect("Attempted move not found!?");
-                            engine.board = engine.board.make(m);
-                            if is_repetition_draw(&engine.keystack, engine.board.hash()) {
-                                println!("1/2-1/2 {{Draw by repetition}}");
-                            }
-                            engine.keystack.push(engine.board.hash());
+                            engine.make_move(m);
                             // Find the next move to make
                             // TODO: Cleanups
-                            let mut pv = ArrayVec::new();
-                            engine.search(&mut pv, &mut tt, protocol);
-                            // Choose the top move
-                            let m = pv[0];
-                            // We must actually make the move locally too
-                            engine.board = engine.board.make(m);
-                            println!("move {m}");
-                            if is_repetition_draw(&engine.keystack, engine.board.hash()) {
-                                println!("1/2-1/2 {{Draw by repetition}}");
-                            }
-                            engine.keystack.push(engine.board.hash());
-                            engine.tc.increment_moves();
+                            let mut pv = Vec::new();
+                            engine.search(&mut pv, &tt, &eval_cache, &options, &interrupts, protocol);
+                            engine.play_xboard(pv, &tt, &eval_cache, &options, &interrupts, protocol);
                         }
-                        Mode::Force => {
+                        Mode::Force | Mode::Analyze => {
                             let m = engine.find_move(from, dest, prom).expect("Attempted move not found!?");
-                            engine.board = engine.board.make(m);
-                            if is_repetition_draw(&engine.keystack, engine.board.hash()) {
-                                println!("1/2-1/2 {{Draw by repetition}}");
-                            }
-                            engine.keystack.push(engine.board.hash());
+                            engine.make_move(m);
                         }
                     }
                 } else {
@@ -0,0 +1,214 @@
+use tinyvec::ArrayVec;
+use yukari_movegen::{Board, Move, Piece};
+
+use crate::{Search, SearchParams};
+
+/// The stages of a `MovePicker`, in the order it goes through them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    TtMove,
+    GoodCaptures,
+    Refutations,
+    Quiets,
+    BadCaptures,
+    Done,
+}
+
+/// Hands out the moves of a position best first, only generating each kind of move once the ones before it are tried.
+pub struct MovePicker {
+    stage: Stage,
+    tt_move: Option<Move>,
+    /// The killers and counter-move, each with its bonus, best first. Those with a positive bonus are tried before the
+    /// quiet moves are generated; the others are ordered among the quiets by their bonus instead of their history.
+    refutations: [(Option<Move>, i32); 3],
+    /// Quiescence only wants captures that don't lose material.
+    captures_only: bool,
+    /// Along with those captures, quiescence may want the quiet moves that give check.
+    quiet_checks: bool,
+    captures_generated: bool,
+    quiets_generated: bool,
+    /// Generated moves not yet picked, with their order: captures that lose material come first, followed by the
+    /// other captures and later by quiet moves that aren't killers or the counter-move.
+    moves: ArrayVec<[(Move, i32); 256]>,
+    /// Where the captures that lose material end in `moves`.
+    bad_captures_end: usize,
+    /// In check, every evasion is generated along with the captures, and the quiet ones wait here.
+    quiet_evasions: ArrayVec<[Move; 256]>,
+    /// How many legal moves have been generated.
+    generated: usize,
+    next_refutation: usize,
+}
+
+impl MovePicker {
+    /// Pick every move, starting with `tt_move` and then the refutations of the previous move, ordered by the killer and
+    /// counter-move bonuses of `params`.
+    pub fn new(tt_move: Option<Move>, killers: [Option<Move>; 2], counter_move: Option<Move>, params: &SearchParams) -> Self {
+        // A counter-move that is also a killer is tried as a killer.
+        let counter_move = counter_move.filter(|m| !killers.contains(&Some(*m)));
+        let mut refutations =
+            [(killers[0], params.killer_bonus), (killers[1], params.killer_bonus - 1), (counter_move, params.counter_bonus)];
+        refutations.sort_by_key(|(_, bonus)| -bonus);
+        Self {
+            stage: Stage::TtMove,
+            tt_move,
+            refutations,
+            captures_only: false,
+            quiet_checks: false,
+            captures_generated: false,
+            quiets_generated: false,
+            moves: ArrayVec::new(),
+            bad_captures_end: 0,
+            quiet_evasions: ArrayVec::new(),
+            generated: 0,
+            next_refutation: 0,
+        }
+    }
+
+    /// Pick only `tt_move` if it is a capture, the captures that don't lose material, and with `quiet_checks`, the
+    /// quiet moves that give check.
+    pub fn captures(tt_move: Option<Move>, quiet_checks: bool, params: &SearchParams) -> Self {
+        let mut picker = Self::new(tt_move, [None; 2], None, params);
+        picker.captures_only = true;
+        picker.quiet_checks = quiet_checks;
+        picker
+    }
+
+    /// The next move to try, or `None` once every move has been picked.
+    pub fn next(&mut self, search: &Search, board: &Board, ply: i32) -> Option<Move> {
+        loop {
+            match self.stage {
+                Stage::TtMove => {
+                    self.stage = Stage::GoodCaptures;
+                    // Forget a TT move that can't be picked, so that later stages don't skip it either.
+                    self.tt_move = self.tt_move.filter(|m| (!self.captures_only || m.is_capture()) && board.is_legal(*m));
+                    if self.tt_move.is_some() {
+                        return self.tt_move;
+                    }
+                }
+                Stage::GoodCaptures => {
+                    self.generate_captures(search, board);
+                    if let Some(m) = pick_best(&mut self.moves, self.bad_captures_end) {
+                        return Some(m);
+                    }
+                    self.stage = if !self.captures_only {
+                        Stage::Refutations
+                    } else if self.quiet_checks {
+                        Stage::Quiets
+                    } else {
+                        Stage::Done
+                    };
+                }
+                Stage::Refutations => match self.refutations.get(self.next_refutation) {
+                    Some(&(refutation, bonus)) if bonus > 0 => {
+                        self.next_refutation += 1;
+                        if let Some(m) = refutation {
+                            if self.tt_move != Some(m) && board.is_legal(m) {
+                                return Some(m);
+                            }
+                        }
+                    }
+                    _ => self.stage = Stage::Quiets,
+                },
+                Stage::Quiets => {
+                    self.generate_quiets(search, board, ply);
+                    if let Some(m) = pick_best(&mut self.moves, self.bad_captures_end) {
+                        return Some(m);
+                    }
+                    self.stage = Stage::BadCaptures;
+                }
+                Stage::BadCaptures => {
+                    if let Some(m) = pick_best(&mut self.moves, 0) {
+                        return Some(m);
+                    }
+                    self.stage = Stage::Done;
+                }
+                Stage::Done => return None,
+            }
+        }
+    }
+
+    /// How many legal moves the position has, generating any that haven't been yet.
+    pub fn legal_moves(&mut self, search: &Search, board: &Board, ply: i32) -> usize {
+        self.generate_captures(search, board);
+        self.generate_quiets(search, board, ply);
+        self.generated
+    }
+
+    fn generate_captures(&mut self, search: &Search, board: &Board) {
+        if self.captures_generated {
+            return;
+        }
+        self.captures_generated = true;
+
+        let mut moves = ArrayVec::new();
+        if board.in_check() {
+            // Capture generation doesn't know about check; only some captures escape it.
+            board.generate(&mut moves);
+            self.generated += moves.len();
+            self.quiet_evasions.extend(moves.iter().copied().filter(|m| !m.is_capture()));
+            moves.retain(|m: &Move| m.is_capture());
+        } else {
+            board.generate_captures(&mut moves);
+            self.generated += moves.len();
+        }
+
+        for m in moves {
+            if self.tt_move == Some(m) {
+                continue;
+            }
+            if !loses_material(board, m) {
+                self.moves.push((m, search.capture_order(board, m)));
+            } else if !self.captures_only {
+                self.moves.push((m, search.capture_order(board, m)));
+                let last = self.moves.len() - 1;
+                self.moves.swap(self.bad_captures_end, last);
+                self.bad_captures_end += 1;
+            }
+        }
+    }
+
+    fn generate_quiets(&mut self, search: &Search, board: &Board, ply: i32) {
+        if self.quiets_generated || (self.captures_only && !self.quiet_checks) {
+            return;
+        }
+        self.quiets_generated = true;
+
+        let mut moves = ArrayVec::new();
+        if board.in_check() {
+            // The captures were generated along with every evasion.
+            self.generate_captures(search, board);
+            moves = std::mem::take(&mut self.quiet_evasions);
+        } else {
+            board.generate(&mut moves);
+            moves.retain(|m: &Move| !m.is_capture());
+            self.generated += moves.len();
+        }
+
+        for m in moves {
+            // The TT move and the refutations with a positive bonus have their own stages.
+            let bonus = self.refutations.iter().find(|(refutation, _)| *refutation == Some(m)).map(|(_, bonus)| *bonus);
+            if self.tt_move == Some(m) || bonus.is_some_and(|bonus| bonus > 0) {
+                continue;
+            }
+            if self.captures_only && !board.make(m).in_check() {
+                continue;
+            }
+            self.moves.push((m, bonus.unwrap_or_else(|| search.history_score(board, m, ply))));
+        }
+    }
+}
+
+/// Remove and return the move with the highest order from `moves[start..]`, leaving the moves before `start` alone.
+fn pick_best(moves: &mut ArrayVec<[(Move, i32); 256]>, start: usize) -> Option<Move> {
+    let (best, _) = moves.iter().enumerate().skip(start).max_by_key(|(_, (_, order))| *order)?;
+    Some(moves.swap_remove(best).0)
+}
+
+/// Whether the capture `m` loses material, according to SEE.
+fn loses_material(board: &Board, m: Move) -> bool {
+    // Taking a piece at least as valuable as the capturer can't lose material.
+    const VALUES: [i32; 6] = [1, 3, 3, 5, 9, 100];
+    let attacker = board.piece_from_square(m.from).unwrap();
+    let victim = board.piece_from_square(m.dest).unwrap_or(Piece::Pawn);
+    VALUES[victim as usize] < VALUES[attacker as usize] && board.static_exchange_evaluation(m) < 0
+}
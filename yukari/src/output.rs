@@ -5,14 +5,37 @@ use indicatif::{ProgressBar, ProgressStyle};
 use tinyvec::ArrayVec;
 use yukari_movegen::{Board, Move};
 
+use crate::{MATE_THRESHOLD, MATE_VALUE};
+
 pub trait Output {
-    fn new_pv(&mut self, board: &Board, depth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move]);
+    #[allow(clippy::too_many_arguments)]
+    fn new_pv(&mut self, board: &Board, depth: i32, seldepth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move]);
+    /// Note that the root move `m` is being searched; formats report this only once the search has run for a while.
     fn new_move(&mut self, board: &Board, depth: i32, time: Duration, nodes: u64, m: Move);
     #[allow(clippy::too_many_arguments)]
     fn complete(
-        &mut self, board: &Board, depth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool, fail_high: bool,
+        &mut self, board: &Board, depth: i32, seldepth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool,
+        fail_high: bool,
     );
     fn abort(&mut self);
+    /// Report how full the TT is, in permille.
+    fn hashfull(&mut self, permille: u32);
+    /// Report on the root move being searched, when asked to.
+    fn status(&mut self, time: Duration, nodes: u64);
+}
+
+/// How long a search runs before reporting each root move it searches.
+const REPORT_MOVES_AFTER: Duration = Duration::from_secs(2);
+
+/// The moves to mate if `score` is a mate score: positive when the side to move mates, negative when it is mated.
+fn mate_in(score: i32) -> Option<i32> {
+    if score >= MATE_THRESHOLD {
+        Some((MATE_VALUE - score + 1) / 2)
+    } else if score <= -MATE_THRESHOLD {
+        Some(-(MATE_VALUE + score + 1) / 2)
+    } else {
+        None
+    }
 }
 
 pub struct Human {
@@ -30,103 +53,104 @@ impl Human {
 }
 
 impl Output for Human {
-    fn new_pv(&mut self, board: &Board, depth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move]) {
+    fn new_pv(&mut self, board: &Board, depth: i32, seldepth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move]) {
         let nodes = if nodes > 1_000_000_000 { format!("{:>8}k", nodes / 1_000) } else { format!("{nodes:>9}") };
 
-        let score = if score >= 9500 {
-            let score = ((10000 - score) as u32).div_ceil(2);
-            format!("+#{score}").green()
-        } else if score <= -9500 {
-            let score = ((10000 + score) as u32).div_ceil(2);
-            format!("-#{score}").red()
-        } else {
-            let score = (score as f32) / 100.0;
-            format!("{score:+7.2}").normal()
+        let score = match mate_in(score) {
+            Some(moves) if score > 0 => format!("+#{moves}").green(),
+            Some(moves) => format!("-#{}", -moves).red(),
+            None => format!("{:+7.2}", (score as f32) / 100.0).normal(),
         };
-        self.progress.println(format!("{depth:>2} {score:>9} {:>8.3} {nodes}\t{}", time.as_secs_f32(), board.pv_to_san(pv)));
+        let depth = format!("{depth}/{seldepth}");
+        self.progress.println(format!("{depth:>5} {score:>9} {:>8.3} {nodes}\t{}", time.as_secs_f32(), board.pv_to_san(pv)));
     }
 
-    fn new_move(&mut self, board: &Board, _depth: i32, _time: Duration, nodes: u64, m: Move) {
+    fn new_move(&mut self, board: &Board, _depth: i32, time: Duration, nodes: u64, m: Move) {
+        if time < REPORT_MOVES_AFTER {
+            return;
+        }
         self.progress.inc(1);
         self.progress.set_message(format!("{} ({} nodes)", board.to_san(m), nodes));
     }
 
     fn complete(
-        &mut self, board: &Board, depth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool, fail_high: bool,
+        &mut self, board: &Board, depth: i32, seldepth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool,
+        fail_high: bool,
     ) {
         self.progress.finish_and_clear();
+        let depth = format!("{depth}/{seldepth}");
         let nodes = if nodes > 1_000_000_000 { format!("{:>8}k", nodes / 1_000) } else { format!("{nodes:>9}") };
-        let score = if score >= 9500 {
-            let score = ((10000 - score) as u32).div_ceil(2);
-            format!("+#{score}").green()
-        } else if score <= -9500 {
-            let score = ((10000 + score) as u32).div_ceil(2);
-            format!("-#{score}").red()
-        } else {
-            let score = (score as f32) / 100.0;
-            format!("{score:+7.2}").normal()
+        let score = match mate_in(score) {
+            Some(moves) if score > 0 => format!("+#{moves}").green(),
+            Some(moves) => format!("-#{}", -moves).red(),
+            None => format!("{:+7.2}", (score as f32) / 100.0).normal(),
         };
         if success {
-            println!("{:>2} {score:>9} {:>8.3} {nodes}\t{}", depth.to_string().bold(), time.as_secs_f32(), board.pv_to_san(pv));
+            println!("{:>5} {score:>9} {:>8.3} {nodes}\t{}", depth.bold(), time.as_secs_f32(), board.pv_to_san(pv));
         } else if fail_high {
-            println!("{:>2} {score:>9} {:>8.3} {nodes}\t{}", depth.to_string().green(), time.as_secs_f32(), board.pv_to_san(pv));
+            println!("{:>5} {score:>9} {:>8.3} {nodes}\t{}", depth.green(), time.as_secs_f32(), board.pv_to_san(pv));
         } else {
-            println!("{:>2} {score:>9} {:>8.3} {nodes}\t{}", depth.to_string().red(), time.as_secs_f32(), board.pv_to_san(pv));
+            println!("{:>5} {score:>9} {:>8.3} {nodes}\t{}", depth.red(), time.as_secs_f32(), board.pv_to_san(pv));
         }
     }
 
     fn abort(&mut self) {
         self.progress.finish_and_clear();
     }
+
+    fn hashfull(&mut self, permille: u32) {
+        println!("{}", format!("hash {:.1}% full", f64::from(permille) / 10.0).dimmed());
+    }
+
+    fn status(&mut self, _time: Duration, _nodes: u64) {
+        /* the progress bar already shows it */
+    }
 }
 
 pub struct Xboard {
     movecount: usize,
     movesleft: usize,
+    /// The depth, root move and moves left (counting it) of the root move being searched.
+    current: Option<(i32, Move, usize)>,
 }
 
 impl Xboard {
     pub fn start(board: &Board) -> Self {
         let mut moves = ArrayVec::new();
         board.generate(&mut moves);
-        Self { movecount: moves.len(), movesleft: moves.len() }
+        Self { movecount: moves.len(), movesleft: moves.len(), current: None }
     }
 }
 
 impl Output for Xboard {
-    fn new_pv(&mut self, _board: &Board, depth: i32, mut score: i32, time: Duration, nodes: u64, pv: &[Move]) {
-        if score >= 9500 {
-            score = 100000 + (10000 - score) / 2;
-        }
-        if score <= -9500 {
-            score = -100000 - (-10000 - score) / 2;
-        }
-        print!("{depth} {score} {} {nodes}", time.as_millis() / 10);
-        for m in pv {
-            print!(" {m}");
+    fn new_pv(&mut self, _board: &Board, depth: i32, seldepth: i32, mut score: i32, time: Duration, nodes: u64, pv: &[Move]) {
+        if let Some(moves) = mate_in(score) {
+            score = 100_000 * score.signum() + moves;
         }
+        // The selective depth is an optional field after the node count, which needs a tab before the PV.
+        let pv = pv.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ");
+        print!("{depth} {score} {} {nodes} {seldepth}\t{pv}", time.as_millis() / 10);
         println!();
     }
 
     fn new_move(&mut self, _board: &Board, depth: i32, time: Duration, nodes: u64, m: Move) {
-        println!("stat01: {} {} {} {} {} {}", time.as_millis() / 10, nodes, depth, self.movesleft, self.movecount, m);
-        self.movesleft -= 1;
+        self.current = Some((depth, m, self.movesleft));
+        self.movesleft = self.movesleft.saturating_sub(1);
+        if time >= REPORT_MOVES_AFTER {
+            self.status(time, nodes);
+        }
     }
 
     fn complete(
-        &mut self, _board: &Board, depth: i32, mut score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool,
-        fail_high: bool,
+        &mut self, _board: &Board, depth: i32, seldepth: i32, mut score: i32, time: Duration, nodes: u64, pv: &[Move],
+        success: bool, fail_high: bool,
     ) {
-        if score >= 9500 {
-            score = 100000 + (10000 - score) / 2;
-        }
-        if score <= -9500 {
-            score = -100000 + (-10000 - score) / 2;
-        }
-        print!("{depth} {score} {} {nodes}", time.as_millis() / 10);
-        for m in pv {
-            print!(" {m}");
+        if let Some(moves) = mate_in(score) {
+            score = 100_000 * score.signum() + moves;
         }
+        // The selective depth is an optional field after the node count, which needs a tab before the PV.
+        let pv = pv.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ");
+        print!("{depth} {score} {} {nodes} {seldepth}\t{pv}", time.as_millis() / 10);
         if success {
             println!();
         } else if fail_high {
@@ -139,25 +163,40 @@ impl Output for Xboard {
     fn abort(&mut self) {
         /* no-op */
     }
+
+    fn hashfull(&mut self, permille: u32) {
+        println!("# hashfull {permille}");
+    }
+
+    fn status(&mut self, time: Duration, nodes: u64) {
+        if let Some((depth, m, movesleft)) = self.current {
+            println!("stat01: {} {} {} {} {} {}", time.as_millis() / 10, nodes, depth, movesleft, self.movecount, m);
+        }
+    }
 }
 
 pub struct Uci {
     moves: u32,
+    line: usize,
 }
 
 impl Uci {
-    pub fn start(_board: &Board) -> Self {
-        Self { moves: 1 }
+    pub fn start(board: &Board) -> Self {
+        Self::start_line(board, 1)
+    }
+
+    /// Report the `line`th best principal variation of a multi-PV search.
+    pub fn start_line(_board: &Board, line: usize) -> Self {
+        Self { moves: 1, line }
     }
 }
 
 impl Output for Uci {
-    fn new_pv(&mut self, _board: &Board, depth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move]) {
-        print!("info depth {depth} score ");
-        if score >= 9500 {
-            print!("mate {} ", 10000 - score);
-        } else if score <= -9500 {
-            print!("mate {} ", -10000 + score);
+    fn new_pv(&mut self, _board: &Board, depth: i32, seldepth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move]) {
+        print!("info depth {depth} seldepth {seldepth} multipv {} score ", self.line);
+        // UCI counts mates in moves, not plies.
+        if let Some(moves) = mate_in(score) {
+            print!("mate {moves} ");
         } else {
             print!("cp {score} ");
         }
@@ -169,18 +208,20 @@ impl Output for Uci {
     }
 
     fn new_move(&mut self, _board: &Board, depth: i32, time: Duration, nodes: u64, m: Move) {
-        println!("info depth {depth} time {} nodes {nodes} currmove {m} currmovenumber {}", time.as_millis(), self.moves);
+        if time >= REPORT_MOVES_AFTER {
+            println!("info depth {depth} time {} nodes {nodes} currmove {m} currmovenumber {}", time.as_millis(), self.moves);
+        }
         self.moves += 1;
     }
 
     fn complete(
-        &mut self, _board: &Board, depth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool, fail_high: bool,
+        &mut self, _board: &Board, depth: i32, seldepth: i32, score: i32, time: Duration, nodes: u64, pv: &[Move], success: bool,
+        fail_high: bool,
     ) {
-        print!("info depth {depth} score ");
-        if score >= 9500 {
-            print!("mate {} ", 10000 - score);
-        } else if score <= -9500 {
-            print!("mate {} ", -10000 + score);
+        print!("info depth {depth} seldepth {seldepth} multipv {} score ", self.line);
+        // UCI counts mates in moves, not plies.
+        if let Some(moves) = mate_in(score) {
+            print!("mate {moves} ");
         } else {
             print!("cp {score} ");
         }
@@ -203,12 +244,20 @@ impl Output for Uci {
     fn abort(&mut self) {
         /* no-op */
     }
+
+    fn hashfull(&mut self, permille: u32) {
+        println!("info hashfull {permille}");
+    }
+
+    fn status(&mut self, _time: Duration, _nodes: u64) {
+        /* UCI has no such request */
+    }
 }
 
 pub struct NoOp;
 
 impl Output for NoOp {
-    fn new_pv(&mut self, _board: &Board, _depth: i32, _score: i32, _time: Duration, _nodes: u64, _pv: &[Move]) {
+    fn new_pv(&mut self, _board: &Board, _depth: i32, _seldepth: i32, _score: i32, _time: Duration, _nodes: u64, _pv: &[Move]) {
         /* no-op */
     }
 
@@ -217,8 +266,8 @@ impl Output for NoOp {
     }
 
     fn complete(
-        &mut self, _board: &Board, _depth: i32, _score: i32, _time: Duration, _nodes: u64, _pv: &[Move], _success: bool,
-        _fail_high: bool,
+        &mut self, _board: &Board, _depth: i32, _seldepth: i32, _score: i32, _time: Duration, _nodes: u64, _pv: &[Move],
+        _success: bool, _fail_high: bool,
     ) {
         /* no-op */
     }
@@ -226,4 +275,31 @@ impl Output for NoOp {
     fn abort(&mut self) {
         /* no-op */
     }
+
+    fn hashfull(&mut self, _permille: u32) {
+        /* no-op */
+    }
+
+    fn status(&mut self, _time: Duration, _nodes: u64) {
+        /* no-op */
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mate_in;
+    use crate::{MATE_THRESHOLD, MATE_VALUE};
+
+    #[test]
+    fn mate_scores_count_moves() {
+        assert_eq!(mate_in(MATE_VALUE - 1), Some(1));
+        assert_eq!(mate_in(MATE_VALUE - 3), Some(2));
+        assert_eq!(mate_in(-MATE_VALUE + 2), Some(-1));
+        assert_eq!(mate_in(-MATE_VALUE + 4), Some(-2));
+        assert_eq!(mate_in(-MATE_VALUE), Some(0));
+        // Big but ordinary scores aren't mates.
+        assert_eq!(mate_in(9500), None);
+        assert_eq!(mate_in(MATE_THRESHOLD - 1), None);
+        assert_eq!(mate_in(-MATE_THRESHOLD + 1), None);
+    }
 }
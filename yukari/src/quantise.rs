@@ -0,0 +1,253 @@
+use std::io::{self, Write};
+
+use rand::{rngs::StdRng, seq::IteratorRandom, SeedableRng};
+use tinyvec::ArrayVec;
+use yukari_movegen::{Board, Colour, HIDDEN_SIZE, OUTPUT_BUCKETS, SCALE};
+
+/// Number of network inputs: 12 piece types on 64 squares.
+const INPUTS: usize = 768;
+
+/// A network as produced by the trainer, before quantisation.
+///
+/// The file is a raw dump of little-endian `f32`s in the same order as the quantised network:
+/// feature weights (`INPUTS` columns of `HIDDEN_SIZE`), feature biases, output weights
+/// (`OUTPUT_BUCKETS` rows of `2 * HIDDEN_SIZE`), and output biases.
+pub struct FloatNetwork {
+    feature_weights: Vec<f32>,
+    feature_bias: Vec<f32>,
+    output_weights: Vec<f32>,
+    output_bias: Vec<f32>,
+}
+
+impl FloatNetwork {
+    /// Parse a raw `f32` dump.
+    ///
+    /// # Errors
+    /// Returns an error if the dump is not exactly the size of a network.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        const FLOATS: usize = INPUTS * HIDDEN_SIZE + HIDDEN_SIZE + OUTPUT_BUCKETS * 2 * HIDDEN_SIZE + OUTPUT_BUCKETS;
+        if bytes.len() != FLOATS * 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected {} bytes of f32 weights, found {}", FLOATS * 4, bytes.len()),
+            ));
+        }
+
+        let mut floats = bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        Ok(Self {
+            feature_weights: floats.by_ref().take(INPUTS * HIDDEN_SIZE).collect(),
+            feature_bias: floats.by_ref().take(HIDDEN_SIZE).collect(),
+            output_weights: floats.by_ref().take(OUTPUT_BUCKETS * 2 * HIDDEN_SIZE).collect(),
+            output_bias: floats.collect(),
+        })
+    }
+
+    /// Evaluate a position from the side to move's perspective, in centipawns.
+    #[must_use]
+    pub fn evaluate(&self, board: &Board) -> f32 {
+        let features = board.eval_features();
+        let mut white = self.feature_bias.clone();
+        let mut black = self.feature_bias.clone();
+        for (white_feature, black_feature) in features {
+            let white_weights = &self.feature_weights[white_feature * HIDDEN_SIZE..(white_feature + 1) * HIDDEN_SIZE];
+            let black_weights = &self.feature_weights[black_feature * HIDDEN_SIZE..(black_feature + 1) * HIDDEN_SIZE];
+            for (i, w) in white.iter_mut().zip(white_weights) {
+                *i += w;
+            }
+            for (i, w) in black.iter_mut().zip(black_weights) {
+                *i += w;
+            }
+        }
+
+        let (us, them) = if board.side() == Colour::White { (white, black) } else { (black, white) };
+        let bucket = board.output_bucket();
+        let weights = &self.output_weights[bucket * 2 * HIDDEN_SIZE..(bucket + 1) * 2 * HIDDEN_SIZE];
+
+        // Squared Clipped `ReLU`, as in the quantised network.
+        let mut output = self.output_bias[bucket];
+        for (input, weight) in us.iter().chain(&them).zip(weights) {
+            let input = input.clamp(0.0, 1.0);
+            output += input * input * weight;
+        }
+        output * (SCALE as f32)
+    }
+
+    /// Quantise the network with the given feature-transformer (`qa`) and output (`qb`) scales.
+    ///
+    /// # Errors
+    /// Returns an error if a weight does not fit in an `i16` after scaling.
+    pub fn quantise(&self, qa: i16, qb: i16) -> io::Result<QuantisedNetwork> {
+        let quantise = |weights: &[f32], scale: f32| -> io::Result<Vec<i16>> {
+            weights
+                .iter()
+                .map(|&w| {
+                    let q = (w * scale).round();
+                    if q < f32::from(i16::MIN) || q > f32::from(i16::MAX) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("weight {w} is out of range when scaled by {scale}"),
+                        ));
+                    }
+                    Ok(q as i16)
+                })
+                .collect()
+        };
+
+        Ok(QuantisedNetwork {
+            qa,
+            qb,
+            feature_weights: quantise(&self.feature_weights, f32::from(qa))?,
+            feature_bias: quantise(&self.feature_bias, f32::from(qa))?,
+            output_weights: quantise(&self.output_weights, f32::from(qb))?,
+            output_bias: quantise(&self.output_bias, f32::from(qa) * f32::from(qb))?,
+        })
+    }
+}
+
+/// A quantised network, laid out as `yukari-movegen` expects it.
+pub struct QuantisedNetwork {
+    qa: i16,
+    qb: i16,
+    feature_weights: Vec<i16>,
+    feature_bias: Vec<i16>,
+    output_weights: Vec<i16>,
+    output_bias: Vec<i16>,
+}
+
+impl QuantisedNetwork {
+    /// Number of output weights whose product with a fully-activated input overflows an `i16`.
+    /// The engine relies on this product fitting, so this should be zero.
+    #[must_use]
+    pub fn overflowing_output_weights(&self) -> usize {
+        self.output_weights.iter().filter(|&&w| i32::from(w).abs() * i32::from(self.qa) > i32::from(i16::MAX)).count()
+    }
+
+    /// Write the network in the format `include_bytes!` expects, including trailing alignment padding.
+    ///
+    /// # Errors
+    /// Returns any error from writing to `f`.
+    pub fn write(&self, f: &mut impl Write) -> io::Result<()> {
+        let mut bytes = 0;
+        for w in self.feature_weights.iter().chain(&self.feature_bias).chain(&self.output_weights).chain(&self.output_bias) {
+            f.write_all(&w.to_le_bytes())?;
+            bytes += 2;
+        }
+        // The network struct is 64-byte aligned.
+        while bytes % 64 != 0 {
+            f.write_all(&[0])?;
+            bytes += 1;
+        }
+        Ok(())
+    }
+
+    /// Evaluate a position from the side to move's perspective, with the same integer arithmetic as the engine.
+    #[must_use]
+    pub fn evaluate(&self, board: &Board) -> i32 {
+        let features = board.eval_features();
+        let mut white = self.feature_bias.clone();
+        let mut black = self.feature_bias.clone();
+        for (white_feature, black_feature) in features {
+            let white_weights = &self.feature_weights[white_feature * HIDDEN_SIZE..(white_feature + 1) * HIDDEN_SIZE];
+            let black_weights = &self.feature_weights[black_feature * HIDDEN_SIZE..(black_feature + 1) * HIDDEN_SIZE];
+            for (i, w) in white.iter_mut().zip(white_weights) {
+                *i = i.wrapping_add(*w);
+            }
+            for (i, w) in black.iter_mut().zip(black_weights) {
+                *i = i.wrapping_add(*w);
+            }
+        }
+
+        let (us, them) = if board.side() == Colour::White { (white, black) } else { (black, white) };
+        let bucket = board.output_bucket();
+        let weights = &self.output_weights[bucket * 2 * HIDDEN_SIZE..(bucket + 1) * 2 * HIDDEN_SIZE];
+
+        let mut output = 0_i32;
+        for (&input, &weight) in us.iter().chain(&them).zip(weights) {
+            let input = input.clamp(0, self.qa);
+            output = output.wrapping_add(i32::from(input) * i32::from(input.wrapping_mul(weight)));
+        }
+
+        let output = (output / i32::from(self.qa)) + i32::from(self.output_bias[bucket]);
+        output * SCALE / (i32::from(self.qa) * i32::from(self.qb))
+    }
+}
+
+/// Positions reached by short random games from the starting position, for comparing networks.
+#[must_use]
+pub fn sample_positions(count: usize) -> Vec<Board> {
+    let mut rng = StdRng::seed_from_u64(0x5955_4b41_5249);
+    let mut positions = Vec::with_capacity(count);
+    while positions.len() < count {
+        let mut board = Board::startpos();
+        for _ in 0..40 {
+            let mut moves = ArrayVec::new();
+            board.generate(&mut moves);
+            let Some(&m) = moves.iter().choose(&mut rng) else { break };
+            board = board.make(m);
+            positions.push(board.clone());
+        }
+    }
+    positions.truncate(count);
+    positions
+}
+
+/// Maximum and mean absolute difference in centipawns between float and quantised inference.
+#[must_use]
+pub fn compare(float: &FloatNetwork, quantised: &QuantisedNetwork, positions: &[Board]) -> (f32, f32) {
+    let mut max = 0.0_f32;
+    let mut total = 0.0_f32;
+    for board in positions {
+        let diff = (float.evaluate(board) - quantised.evaluate(board) as f32).abs();
+        max = max.max(diff);
+        total += diff;
+    }
+    (max, total / positions.len() as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use yukari_movegen::{HIDDEN_SIZE, OUTPUT_BUCKETS, QA, QB};
+
+    use super::{sample_positions, FloatNetwork, INPUTS};
+
+    const NET: &[u8] = include_bytes!("../../yukari_2072eadd.bin");
+
+    /// Recover float weights from the embedded network, so they quantise back to it exactly.
+    fn dequantised() -> FloatNetwork {
+        let weights = NET.chunks_exact(2).map(|b| f32::from(i16::from_le_bytes([b[0], b[1]])));
+        let mut bytes = Vec::new();
+        for (i, w) in weights.enumerate() {
+            let w = if i < (INPUTS + 1) * HIDDEN_SIZE {
+                w / f32::from(QA)
+            } else if i < (INPUTS + 1 + 2 * OUTPUT_BUCKETS) * HIDDEN_SIZE {
+                w / f32::from(QB)
+            } else if i < (INPUTS + 1 + 2 * OUTPUT_BUCKETS) * HIDDEN_SIZE + OUTPUT_BUCKETS {
+                w / (f32::from(QA) * f32::from(QB))
+            } else {
+                // Alignment padding.
+                break;
+            };
+            bytes.extend_from_slice(&w.to_le_bytes());
+        }
+        FloatNetwork::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn requantise_roundtrip() {
+        let quantised = dequantised().quantise(QA, QB).unwrap();
+        let mut bytes = Vec::new();
+        quantised.write(&mut bytes).unwrap();
+        // Ignore the padding, which bullet fills with its name.
+        let weights = 2 * ((INPUTS + 1 + 2 * OUTPUT_BUCKETS) * HIDDEN_SIZE + OUTPUT_BUCKETS);
+        assert_eq!(bytes.len(), NET.len());
+        assert!(bytes[..weights] == NET[..weights]);
+    }
+
+    #[test]
+    fn quantised_matches_engine() {
+        let quantised = dequantised().quantise(QA, QB).unwrap();
+        for board in sample_positions(200) {
+            assert_eq!(quantised.evaluate(&board), board.eval(board.side()));
+        }
+    }
+}
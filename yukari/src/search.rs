@@ -1,15 +1,23 @@
 use std::{
-    cmp::Ordering,
-    sync::atomic::AtomicU64,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::atomic::{AtomicBool, AtomicU64},
     time::{Duration, Instant},
 };
 
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use tinyvec::ArrayVec;
-use yukari_movegen::{Board, Move};
+use yukari_movegen::{Board, Move, MoveType, Piece, Square};
 
-use crate::output;
+use crate::{movepicker::MovePicker, output};
 
-const MATE_VALUE: i32 = 10_000;
+/// The score of mating at the root; a mate `n` plies away scores `MATE_VALUE - n`.
+pub const MATE_VALUE: i32 = 10_000;
+
+/// The deepest ply the search can reach; this also bounds the length of a PV.
+pub const MAX_PLY: usize = 128;
+
+/// Scores at least this close to `MATE_VALUE` are mates, whose distance from the root depends on the ply.
+pub const MATE_THRESHOLD: i32 = MATE_VALUE - MAX_PLY as i32;
 
 #[derive(Clone)]
 pub struct SearchParams {
@@ -22,6 +30,28 @@ pub struct SearchParams {
     pub hist_bonus_mul: i32,
     pub hist_pen_base: i32,
     pub hist_pen_mul: i32,
+    /// Captures and quiets are pruned by SEE at this depth or below.
+    pub see_prune_depth: i32,
+    /// Captures losing more than this times depth squared, in centipawns, are pruned.
+    pub see_capture_margin: i32,
+    /// Quiets losing more than this times depth, in centipawns, are pruned.
+    pub see_quiet_margin: i32,
+    /// Quiescence skips captures that can't raise alpha even with this much positional gain.
+    pub delta_margin: i32,
+    /// The TT move is tested for being singular from this depth up.
+    pub singular_depth: i32,
+    /// Every other move must fail low against the TT score less this times depth for the TT move to be singular.
+    pub singular_margin: i32,
+    /// A singular TT move whose alternatives fail low by this much more is extended twice.
+    pub double_extension_margin: i32,
+    /// The most double extensions allowed on the way to a node.
+    pub max_double_extensions: i32,
+    /// The order of the first killer among quiet moves; positive tries it before any are generated.
+    pub killer_bonus: i32,
+    /// The order of the counter-move among quiet moves; positive tries it before any are generated.
+    pub counter_bonus: i32,
+    /// Whether quiescence also tries quiet checks at its first ply.
+    pub qsearch_checks: bool,
 }
 
 impl Default for SearchParams {
@@ -36,6 +66,17 @@ impl Default for SearchParams {
             hist_bonus_mul: 300,
             hist_pen_base: 250,
             hist_pen_mul: 300,
+            see_prune_depth: 6,
+            see_capture_margin: 30,
+            see_quiet_margin: 60,
+            delta_margin: 200,
+            singular_depth: 8,
+            singular_margin: 2,
+            double_extension_margin: 20,
+            max_double_extensions: 6,
+            killer_bonus: 32768,
+            counter_bonus: 8192,
+            qsearch_checks: false,
         }
     }
 }
@@ -46,7 +87,15 @@ pub fn is_repetition_draw(keystack: &[u64], hash: u64) -> bool {
     keystack.iter().filter(|key| **key == hash).count() >= 3
 }
 
-#[derive(Clone, Default)]
+/// Adjust a static evaluation by the pawn-structure correction history, as the search does.
+#[must_use]
+pub fn eval_with_corrhist(corrhist: &[[i32; 16384]; 2], board: &Board, eval: i32) -> i32 {
+    const CORRHIST_GRAIN: i32 = 256;
+    let entry = &corrhist[board.side() as usize][board.hash_pawns() as usize & 16383];
+    (eval + entry / CORRHIST_GRAIN).clamp(-MATE_VALUE + 1, MATE_VALUE - 1)
+}
+
+#[derive(Clone, Copy, Default)]
 #[repr(u8)]
 enum TtFlags {
     #[default]
@@ -57,41 +106,318 @@ enum TtFlags {
 
 #[derive(Default)]
 #[repr(align(16))]
-pub struct TtEntry {
+struct TtEntry {
     key: AtomicU64,
     data: AtomicU64,
 }
 
+/// A cache line of TT entries, all for positions with the same index.
+#[derive(Default)]
+#[repr(align(64))]
+pub struct TtBucket {
+    entries: [TtEntry; 4],
+}
+
 #[derive(Default)]
 struct TtData {
-    flags: TtFlags,
+    /// A `TtFlags` in the low two bits, and the generation of the search that wrote it in the rest.
+    flags: u8,
     depth: u8,
     score: i16,
     m: Option<Move>,
 }
 
-const _TT_ENTRY_IS_16_BYTE: () = assert!(std::mem::size_of::<TtEntry>() == 16);
-const _TT_DATA_IS_8_BYTE: () = assert!(std::mem::size_of::<TtData>() == 8);
+impl TtData {
+    const fn bound(&self) -> TtFlags {
+        match self.flags & 3 {
+            0 => TtFlags::Exact,
+            1 => TtFlags::Upper,
+            _ => TtFlags::Lower,
+        }
+    }
+
+    /// How many searches ago this entry was written.
+    const fn age(&self, generation: u8) -> u8 {
+        generation.wrapping_sub(self.flags >> 2) & (TT_GENERATIONS - 1)
+    }
 
-pub fn allocate_tt(megabytes: usize) -> Vec<TtEntry> {
-    let target_bytes = megabytes * 1024 * 1024;
+    /// Pack into the layout kept in `TtEntry::data` and in saved files: flags, depth and score (little-endian) a byte or two
+    /// each, then the move's from-square, to-square, `MoveType` and promotion piece a byte each, or four 0xFF bytes for none.
+    fn pack(&self) -> u64 {
+        let m = self.m.map_or(u32::MAX, |m| {
+            let prom = m.prom.map_or(0xFF, |piece| piece as u8);
+            u32::from_le_bytes([m.from.into_inner(), m.dest.into_inner(), m.kind as u8, prom])
+        });
+        u64::from(self.flags) | u64::from(self.depth) << 8 | u64::from(self.score as u16) << 16 | u64::from(m) << 32
+    }
 
-    let mut size = 1_usize;
-    loop {
-        if size > target_bytes {
-            break;
+    /// The entry `pack` made `data` from, or `None` if `data` isn't a valid entry.
+    fn unpack(data: u64) -> Option<Self> {
+        let flags = data as u8;
+        if flags & 3 == 3 {
+            return None;
         }
-        size *= 2;
+        let score = (data >> 16) as u16 as i16;
+        if i32::from(score).abs() > MATE_VALUE {
+            return None;
+        }
+        let m = (data >> 32) as u32;
+        let m = if m == u32::MAX {
+            None
+        } else {
+            let [from, dest, kind, prom] = m.to_le_bytes();
+            let kind = match kind {
+                0 => MoveType::Normal,
+                1 => MoveType::Capture,
+                2 => MoveType::Castle,
+                3 => MoveType::DoublePush,
+                4 => MoveType::EnPassant,
+                5 => MoveType::Promotion,
+                6 => MoveType::CapturePromotion,
+                _ => return None,
+            };
+            let prom = match prom {
+                0xFF => None,
+                1 => Some(Piece::Knight),
+                2 => Some(Piece::Bishop),
+                3 => Some(Piece::Rook),
+                4 => Some(Piece::Queen),
+                _ => return None,
+            };
+            if prom.is_some() != matches!(kind, MoveType::Promotion | MoveType::CapturePromotion) {
+                return None;
+            }
+            Some(Move::new(Square::try_from(from).ok()?, Square::try_from(dest).ok()?, kind, prom))
+        };
+        Some(Self { flags, depth: (data >> 8) as u8, score, m })
     }
-    size /= 2;
-    size /= std::mem::size_of::<TtEntry>();
+}
 
-    let mut tt: Vec<TtEntry> = Vec::new();
+/// Generations wrap around after this many searches, which must fit in the six spare bits of `TtData::flags`.
+const TT_GENERATIONS: u8 = 64;
+
+const _TT_ENTRY_IS_16_BYTE: () = assert!(std::mem::size_of::<TtEntry>() == 16);
+const _TT_BUCKET_IS_64_BYTE: () = assert!(std::mem::size_of::<TtBucket>() == 64);
+
+pub fn allocate_tt(megabytes: usize) -> Vec<TtBucket> {
+    // Indexing by multiply-shift means the size needn't be a power of two.
+    let size = megabytes * 1024 * 1024 / std::mem::size_of::<TtBucket>();
+
+    let mut tt: Vec<TtBucket> = Vec::new();
     tt.resize_with(size, Default::default);
-    println!("# Allocated {} bytes of hash", size * std::mem::size_of::<TtEntry>());
+    println!("# Allocated {} bytes of hash", size * std::mem::size_of::<TtBucket>());
     tt
 }
 
+/// The bucket of `tt` for `hash`, mapping hashes evenly onto buckets by multiply-shift.
+fn tt_bucket(tt: &[TtBucket], hash: u64) -> &TtBucket {
+    &tt[((u128::from(hash) * tt.len() as u128) >> 64) as usize]
+}
+
+/// Empty every entry of `tt`, sharing the work between threads.
+pub fn clear_tt(tt: &[TtBucket]) {
+    tt.par_iter().for_each(|bucket| {
+        for entry in &bucket.entries {
+            entry.key.store(0, std::sync::atomic::Ordering::Relaxed);
+            entry.data.store(0, std::sync::atomic::Ordering::Relaxed);
+        }
+    });
+}
+
+/// Identifies a saved TT, followed by `TT_FORMAT_VERSION`.
+const TT_MAGIC: &[u8; 8] = b"YUKARITT";
+/// Bump this whenever the layout of `TtData::pack` or of the saved file changes.
+const TT_FORMAT_VERSION: u32 = 2;
+/// The size of a saved entry: its hash, then its packed data.
+const TT_SAVED_ENTRY_BYTES: u64 = 16;
+
+/// Write `tt` to `w`: a header of magic, format version and bucket count, then every entry's hash and packed data, with
+/// empty entries all zero.
+pub fn save_tt(tt: &[TtBucket], w: &mut impl Write) -> io::Result<()> {
+    w.write_all(TT_MAGIC)?;
+    w.write_all(&TT_FORMAT_VERSION.to_le_bytes())?;
+    w.write_all(&(tt.len() as u64).to_le_bytes())?;
+    for entry in tt.iter().flat_map(|bucket| &bucket.entries) {
+        let key = entry.key.load(std::sync::atomic::Ordering::Relaxed);
+        let data = entry.data.load(std::sync::atomic::Ordering::Relaxed);
+        w.write_all(&(key ^ data).to_le_bytes())?;
+        w.write_all(&data.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Replace the contents of `tt` with a table saved by `save_tt`, which may have had a different size.
+///
+/// Each saved entry is rehashed into `tt`; when a bucket overflows, the shallowest entries are lost. Entries that don't
+/// decode are dropped, and a file whose length doesn't match its bucket count is rejected.
+pub fn load_tt(tt: &[TtBucket], r: &mut (impl Read + Seek)) -> io::Result<()> {
+    fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        r.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != TT_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a saved hash table"));
+    }
+    let mut version = [0; 4];
+    r.read_exact(&mut version)?;
+    let version = u32::from_le_bytes(version);
+    if version != TT_FORMAT_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported hash table version {version}")));
+    }
+    let buckets = read_u64(r)?;
+
+    let position = r.stream_position()?;
+    let end = r.seek(SeekFrom::End(0))?;
+    r.seek(SeekFrom::Start(position))?;
+    let entries = buckets
+        .checked_mul(4)
+        .filter(|entries| entries.checked_mul(TT_SAVED_ENTRY_BYTES) == Some(end - position))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "hash table size doesn't match the file"))?;
+
+    clear_tt(tt);
+    for _ in 0..entries {
+        let hash = read_u64(r)?;
+        let data = read_u64(r)?;
+        if hash == 0 && data == 0 {
+            continue;
+        }
+        let Some(entry) = TtData::unpack(data) else {
+            continue;
+        };
+        let data = entry.pack();
+        let key = hash ^ data;
+        let depth = |data: u64| TtData::unpack(data).map_or(0, |entry| entry.depth);
+
+        // Take an empty entry if there is one, otherwise the shallowest if it is shallower than this one.
+        let entries = &tt_bucket(tt, hash).entries;
+        let victim = entries
+            .iter()
+            .min_by_key(|entry| {
+                let entry_data = entry.data.load(std::sync::atomic::Ordering::Relaxed);
+                let empty = entry.key.load(std::sync::atomic::Ordering::Relaxed) == 0 && entry_data == 0;
+                (!empty, depth(entry_data))
+            })
+            .unwrap();
+        let victim_data = victim.data.load(std::sync::atomic::Ordering::Relaxed);
+        let victim_empty = victim.key.load(std::sync::atomic::Ordering::Relaxed) == 0 && victim_data == 0;
+        if victim_empty || depth(victim_data) < entry.depth {
+            victim.key.store(key, std::sync::atomic::Ordering::Relaxed);
+            victim.data.store(data, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+/// A cached static evaluation: the upper 48 bits of the Zobrist hash, and the raw network score in the low 16 bits.
+#[derive(Default)]
+pub struct EvalCacheEntry(AtomicU64);
+
+pub fn allocate_eval_cache(megabytes: usize) -> Vec<EvalCacheEntry> {
+    let size = 1_usize << (megabytes * 1024 * 1024 / std::mem::size_of::<EvalCacheEntry>()).ilog2();
+    let mut cache: Vec<EvalCacheEntry> = Vec::new();
+    cache.resize_with(size, Default::default);
+    println!("# Allocated {} bytes of eval cache", size * std::mem::size_of::<EvalCacheEntry>());
+    cache
+}
+
+/// State shared between every thread searching the same position.
+#[derive(Default)]
+pub struct SharedSearch<'a> {
+    stop: AtomicBool,
+    nodes: AtomicU64,
+    /// Set from outside the search (e.g. by a `stop` command) to end it early.
+    interrupt: Option<&'a AtomicBool>,
+    /// Set while thinking on the opponent's time, when the time limits don't apply yet.
+    pondering: Option<&'a AtomicBool>,
+}
+
+impl<'a> SharedSearch<'a> {
+    #[must_use]
+    pub const fn new(interrupt: &'a AtomicBool, pondering: &'a AtomicBool) -> Self {
+        Self { stop: AtomicBool::new(false), nodes: AtomicU64::new(0), interrupt: Some(interrupt), pondering: Some(pondering) }
+    }
+
+    /// Ask every thread using this state to stop searching.
+    pub fn stop(&self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn stopped(&self) -> bool {
+        self.stop.load(std::sync::atomic::Ordering::Relaxed)
+            || self.interrupt.is_some_and(|interrupt| interrupt.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    #[must_use]
+    pub fn pondering(&self) -> bool {
+        self.pondering.is_some_and(|pondering| pondering.load(std::sync::atomic::Ordering::Relaxed))
+    }
+}
+
+/// Scores for a quiet move following some earlier move, indexed by that move's coloured piece and to-square, then this
+/// move's coloured piece and to-square.
+type ContinuationHistory = [[[[i16; 64]; 12]; 64]; 12];
+
+/// How well quiet moves have done in previous searches, for move ordering.
+#[derive(Clone)]
+pub struct History {
+    /// Indexed by side to move, piece and to-square.
+    piece_to: [[[i16; 64]; 6]; 2],
+    /// Indexed by the move one ply earlier, then by the move two plies earlier.
+    continuation: [Box<ContinuationHistory>; 2],
+    /// Indexed by the capturing coloured piece, to-square and victim.
+    capture: [[[i16; 6]; 64]; 12],
+}
+
+impl History {
+    #[must_use]
+    pub fn new() -> Self {
+        let continuation = || vec![[[[0; 64]; 12]; 64]; 12].into_boxed_slice().try_into().unwrap();
+        Self { piece_to: [[[0; 64]; 6]; 2], continuation: [continuation(), continuation()], capture: [[[0; 6]; 64]; 12] }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Move `entry` towards `bonus`, by less the closer it already is to the limit.
+fn apply_history_gravity(entry: &mut i16, bonus: i32) {
+    const HISTORY_MAX: i32 = 16384;
+    let bonus = bonus.clamp(-HISTORY_MAX, HISTORY_MAX);
+    let bonus = bonus - i32::from(*entry) * bonus.abs() / HISTORY_MAX;
+    *entry += bonus as i16;
+}
+
+/// A move made on the way to the current node, with the coloured piece that made it.
+#[derive(Clone, Copy)]
+struct StackMove {
+    m: Move,
+    /// Six times the colour, plus the piece.
+    piece: usize,
+}
+
+/// What the search keeps about each ply on the way to the current node.
+#[derive(Clone, Copy, Default)]
+struct StackEntry {
+    /// The static eval, with correction history, or `None` in check.
+    eval: Option<i32>,
+    /// The move being searched, or `None` for a null move.
+    m: Option<StackMove>,
+    /// The move left out while testing whether it is singular.
+    excluded: Option<Move>,
+    /// Two quiet moves that recently caused beta cutoffs, the most recent first.
+    killers: [Option<Move>; 2],
+    /// How many double extensions were made on the way here.
+    double_extensions: i32,
+}
+
 pub struct Search<'a> {
     nodes: u64,
     qnodes: u64,
@@ -103,19 +429,45 @@ pub struct Search<'a> {
     beta_cutoffs: u64,
     q_beta_cutoff_index: u64,
     q_beta_cutoffs: u64,
+    eval_cache_probes: u64,
+    eval_cache_hits: u64,
+    flushed_nodes: u64,
+    /// When the search started, or when pondering last ended: our clock doesn't run on the opponent's time.
     start: Instant,
     stop_after: Option<Instant>,
-    history: &'a mut [[i16; 64]; 64],
-    tt: &'a [TtEntry],
+    /// Nodes after which no new iteration should be started.
+    soft_nodes: Option<u64>,
+    /// Nodes after which the search stops immediately.
+    hard_nodes: Option<u64>,
+    history: &'a mut History,
+    tt: &'a [TtBucket],
+    /// The generation this search writes to the TT, so entries from older searches can be replaced first.
+    tt_generation: u8,
+    eval_cache: &'a [EvalCacheEntry],
     corrhist: &'a mut [[i32; 16384]; 2],
     params: &'a SearchParams,
+    shared: &'a SharedSearch<'a>,
+    /// Set from outside the search to ask for a report on the root move being searched.
+    status_request: Option<&'a AtomicBool>,
+    /// The moves to consider at the root; empty for all of them.
+    root_moves: Vec<Move>,
+    /// A triangular PV table: row `ply` holds the PV of the node being searched at that ply.
+    pv_table: Vec<[Move; MAX_PLY]>,
+    pv_length: [usize; MAX_PLY],
+    /// The deepest ply reached since the search at the root began.
+    seldepth: i32,
+    /// Per-ply state for every node on the way to the current one.
+    stack: [StackEntry; MAX_PLY],
+    /// The quiet move that last refuted each previous move, indexed by that move's from and to squares.
+    counter_moves: [[Option<Move>; 64]; 64],
 }
 
 impl<'a> Search<'a> {
     #[must_use]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        start: Instant, stop_after: Option<Instant>, tt: &'a [TtEntry], history: &'a mut [[i16; 64]; 64],
-        corrhist: &'a mut [[i32; 16384]; 2], params: &'a SearchParams,
+        start: Instant, stop_after: Option<Instant>, tt: &'a [TtBucket], eval_cache: &'a [EvalCacheEntry],
+        history: &'a mut History, corrhist: &'a mut [[i32; 16384]; 2], params: &'a SearchParams, shared: &'a SharedSearch<'a>,
     ) -> Self {
         Self {
             nodes: 0,
@@ -128,15 +480,52 @@ impl<'a> Search<'a> {
             beta_cutoffs: 0,
             q_beta_cutoff_index: 0,
             q_beta_cutoffs: 0,
+            eval_cache_probes: 0,
+            eval_cache_hits: 0,
+            flushed_nodes: 0,
             start,
             stop_after,
+            soft_nodes: None,
+            hard_nodes: None,
             history,
             tt,
+            tt_generation: 0,
+            eval_cache,
             corrhist,
             params,
+            shared,
+            status_request: None,
+            root_moves: Vec::new(),
+            pv_table: vec![[Move::default(); MAX_PLY]; MAX_PLY],
+            pv_length: [0; MAX_PLY],
+            seldepth: 0,
+            stack: [StackEntry::default(); MAX_PLY],
+            counter_moves: [[None; 64]; 64],
         }
     }
 
+    /// Report the current root move whenever `request` is set, clearing it again.
+    #[must_use]
+    pub const fn with_status_request(mut self, request: &'a AtomicBool) -> Self {
+        self.status_request = Some(request);
+        self
+    }
+
+    /// Write TT entries as generation `generation`, which should increase with every new search.
+    #[must_use]
+    pub const fn with_tt_generation(mut self, generation: u8) -> Self {
+        self.tt_generation = generation % TT_GENERATIONS;
+        self
+    }
+
+    /// Limit the nodes searched by every thread sharing this search's state.
+    #[must_use]
+    pub const fn with_node_limits(mut self, soft: Option<u64>, hard: Option<u64>) -> Self {
+        self.soft_nodes = soft;
+        self.hard_nodes = hard;
+        self
+    }
+
     fn update_corrhist(&mut self, board: &Board, depth: i32, diff: i32) {
         const CORRHIST_GRAIN: i32 = 256;
         const CORRHIST_WEIGHT_SCALE: i32 = 256;
@@ -149,30 +538,146 @@ impl<'a> Search<'a> {
             .clamp(-CORRHIST_MAX, CORRHIST_MAX);
     }
 
+    /// Static evaluation of the side to move, from the eval cache if possible.
+    fn static_eval(&mut self, board: &Board) -> i32 {
+        const KEY_MASK: u64 = !0xFFFF;
+        let entry = &self.eval_cache[(board.hash() & ((self.eval_cache.len() - 1) as u64)) as usize];
+        let data = entry.0.load(std::sync::atomic::Ordering::Relaxed);
+
+        self.eval_cache_probes += 1;
+        if data & KEY_MASK == board.hash() & KEY_MASK {
+            self.eval_cache_hits += 1;
+            return i32::from(data as u16 as i16);
+        }
+
+        let eval = board.eval(board.side()).clamp(i32::from(i16::MIN), i32::from(i16::MAX));
+        entry.0.store((board.hash() & KEY_MASK) | u64::from(eval as i16 as u16), std::sync::atomic::Ordering::Relaxed);
+        eval
+    }
+
     fn eval_with_corrhist(&self, board: &Board, eval: i32) -> i32 {
-        const CORRHIST_GRAIN: i32 = 256;
-        let entry = &self.corrhist[board.side() as usize][board.hash_pawns() as usize & 16383];
-        (eval + entry / CORRHIST_GRAIN).clamp(-MATE_VALUE + 1, MATE_VALUE - 1)
+        eval_with_corrhist(self.corrhist, board, eval)
+    }
+
+    /// The coloured piece index of the piece making the move `m` on `board`.
+    fn coloured_piece(board: &Board, m: Move) -> usize {
+        usize::from(board.side()) * 6 + board.piece_from_square(m.from).unwrap() as usize
     }
 
-    fn update_history(&mut self, m: Move, bonus: i32) {
-        const HISTORY_MAX: i32 = 16384;
-        let bonus = bonus.clamp(-HISTORY_MAX, HISTORY_MAX);
-        let history = &mut self.history[m.from.into_inner() as usize][m.dest.into_inner() as usize];
-        let bonus = bonus - (*history as i32) * bonus.abs() / HISTORY_MAX;
-        *history += bonus as i16;
+    /// The move made `back` plies before the node at `ply`, if there was one and it wasn't a null move.
+    fn earlier_move(&self, ply: i32, back: i32) -> Option<StackMove> {
+        if ply < back {
+            return None;
+        }
+        self.stack[(ply - back) as usize].m
     }
 
-    fn quiesce(&mut self, board: &Board, mut alpha: i32, beta: i32, pv: &mut ArrayVec<[Move; 64]>, ply: i32) -> i32 {
-        let mut best_score = self.eval_with_corrhist(board, board.eval(board.side()));
+    /// The move that led to the node at `ply`, if it wasn't a null move.
+    fn previous_move(&self, ply: i32) -> Option<Move> {
+        self.earlier_move(ply, 1).map(|previous| previous.m)
+    }
 
-        pv.set_len(0);
+    /// Whether the static eval at `ply` is better than at the last ply the side to move was out of check, two or four plies
+    /// ago; when it was in check at both, there is nothing to be worse than.
+    fn improving(&self, ply: i32) -> bool {
+        let Some(eval) = self.stack[ply as usize].eval else {
+            return false;
+        };
+        [2, 4]
+            .into_iter()
+            .filter(|back| ply >= *back)
+            .find_map(|back| self.stack[(ply - back) as usize].eval)
+            .is_none_or(|earlier| eval > earlier)
+    }
+
+    /// The piece-to history of the quiet move `m` at `ply`, plus its continuation histories.
+    pub(crate) fn history_score(&self, board: &Board, m: Move, ply: i32) -> i32 {
+        let piece = Self::coloured_piece(board, m);
+        let dest = m.dest.into_inner() as usize;
+        let mut score = i32::from(self.history.piece_to[piece / 6][piece % 6][dest]);
+        for (back, continuation) in (1..).zip(&self.history.continuation) {
+            if let Some(earlier) = self.earlier_move(ply, back) {
+                score += i32::from(continuation[earlier.piece][earlier.m.dest.into_inner() as usize][piece][dest]);
+            }
+        }
+        score
+    }
+
+    fn update_history(&mut self, board: &Board, m: Move, ply: i32, bonus: i32) {
+        let piece = Self::coloured_piece(board, m);
+        let dest = m.dest.into_inner() as usize;
+        apply_history_gravity(&mut self.history.piece_to[piece / 6][piece % 6][dest], bonus);
+        for back in 1..=2 {
+            if let Some(earlier) = self.earlier_move(ply, back) {
+                let continuation = &mut self.history.continuation[back as usize - 1];
+                apply_history_gravity(&mut continuation[earlier.piece][earlier.m.dest.into_inner() as usize][piece][dest], bonus);
+            }
+        }
+    }
+
+    /// The piece captured by `m`, which for en passant isn't on the destination square.
+    fn victim(board: &Board, m: Move) -> Piece {
+        board.piece_from_square(m.dest).unwrap_or(Piece::Pawn)
+    }
+
+    /// Roughly how much material the capture `m` wins in centipawns, if it isn't recaptured.
+    fn capture_gain(board: &Board, m: Move) -> i32 {
+        const PIECE_VALUES: [i32; 6] = [100, 300, 300, 500, 900, 0];
+        let promotion = m.prom.map_or(0, |piece| PIECE_VALUES[piece as usize] - PIECE_VALUES[Piece::Pawn as usize]);
+        PIECE_VALUES[Self::victim(board, m) as usize] + promotion
+    }
+
+    fn update_capture_history(&mut self, board: &Board, m: Move, bonus: i32) {
+        let entry = &mut self.history.capture[Self::coloured_piece(board, m)][m.dest.into_inner() as usize];
+        apply_history_gravity(&mut entry[Self::victim(board, m) as usize], bonus);
+    }
+
+    /// How early to try the capture `m`: by most valuable victim, with capture history deciding between similar victims.
+    pub(crate) fn capture_order(&self, board: &Board, m: Move) -> i32 {
+        const VICTIM_VALUES: [i32; 6] = [1, 3, 3, 5, 9, 0];
+        let victim = Self::victim(board, m) as usize;
+        let history = self.history.capture[Self::coloured_piece(board, m)][m.dest.into_inner() as usize][victim];
+        VICTIM_VALUES[victim] * 1024 + i32::from(history) / 16
+    }
+
+    /// The quiet move that last refuted the move leading to `ply`.
+    fn counter_move(&self, ply: i32) -> Option<Move> {
+        let previous = self.previous_move(ply)?;
+        self.counter_moves[previous.from.into_inner() as usize][previous.dest.into_inner() as usize]
+    }
+
+    /// Remember the quiet move `m` as having caused a beta cutoff at `ply`.
+    fn update_refutations(&mut self, m: Move, ply: i32) {
+        let killers = &mut self.stack[ply as usize].killers;
+        if killers[0] != Some(m) {
+            killers[1] = killers[0];
+            killers[0] = Some(m);
+        }
+        if let Some(previous) = self.previous_move(ply) {
+            self.counter_moves[previous.from.into_inner() as usize][previous.dest.into_inner() as usize] = Some(m);
+        }
+    }
+
+    /// Search only captures (or evasions, in check) until the position is quiet; `depth` is 0 at the first ply and
+    /// negative below it.
+    fn quiesce(&mut self, board: &Board, depth: i32, mut alpha: i32, beta: i32, ply: i32) -> i32 {
+        self.pv_length[ply as usize] = 0;
+        self.seldepth = self.seldepth.max(ply);
 
         // Emergency bailout
-        if ply == 63 {
-            return best_score;
+        if ply >= MAX_PLY as i32 - 1 {
+            let eval = self.static_eval(board);
+            return self.eval_with_corrhist(board, eval);
         }
 
+        // Standing pat in check would ignore the threat, so search every evasion instead; with none, it's mate.
+        let mut best_score = if board.in_check() {
+            -MATE_VALUE + ply
+        } else {
+            let eval = self.static_eval(board);
+            self.eval_with_corrhist(board, eval)
+        };
+
         if best_score >= beta {
             return best_score;
         }
@@ -185,61 +690,75 @@ impl<'a> Search<'a> {
             }
         }
 
+        let futility_base = best_score + self.params.delta_margin;
+        let mut picker = if board.in_check() {
+            MovePicker::new(tt_move, self.stack[ply as usize].killers, self.counter_move(ply), self.params)
+        } else {
+            MovePicker::captures(tt_move, depth == 0 && self.params.qsearch_checks, self.params)
+        };
         let mut index = 0;
-        board.generate_captures_incremental(|m| {
+        while let Some(m) = picker.next(self, board, ply) {
+            // Delta pruning: even winning the captured piece for free wouldn't be enough.
+            if !board.in_check() && m.is_capture() && futility_base + Self::capture_gain(board, m) <= alpha {
+                continue;
+            }
+
             self.qnodes += 1;
             if alpha == beta - 1 {
                 self.zw_qnodes += 1;
             }
 
             let board = board.make(m);
-            let mut child_pv = ArrayVec::new();
-            let score = -self.quiesce(&board, -beta, -alpha, &mut child_pv, ply + 1);
+            let score = -self.quiesce(&board, depth - 1, -beta, -alpha, ply + 1);
+
+            if self.node_limit_reached() {
+                return best_score;
+            }
 
             best_score = best_score.max(score);
 
             if score >= beta {
                 self.q_beta_cutoff_index += index;
                 self.q_beta_cutoffs += 1;
-                return false;
+                break;
             }
 
             if score > alpha {
                 alpha = score;
-                pv.set_len(0);
-                pv.push(m);
-                for m in child_pv {
-                    pv.push(m);
-                }
+                self.update_pv(ply, m);
             }
 
             index += 1;
-
-            true
-        });
+        }
 
         best_score
     }
 
+    /// The TT entry for `board`, if there is one.
+    fn tt_entry(&self, board: &Board) -> Option<TtData> {
+        for entry in &tt_bucket(self.tt, board.hash()).entries {
+            let entry_key = entry.key.load(std::sync::atomic::Ordering::Relaxed);
+            let entry_data = entry.data.load(std::sync::atomic::Ordering::Relaxed);
+            if entry_key ^ entry_data == board.hash() {
+                return TtData::unpack(entry_data);
+            }
+        }
+        None
+    }
+
     fn probe_tt(
         &self, board: &Board, depth: i32, ply: i32, lower_bound: i32, upper_bound: i32, m: &mut Option<Move>,
     ) -> Option<i32> {
-        let entry = (board.hash() & ((self.tt.len() - 1) as u64)) as usize;
-        let entry = &self.tt[entry];
-        let entry_key = entry.key.load(std::sync::atomic::Ordering::Relaxed);
-        let entry_data = entry.data.load(std::sync::atomic::Ordering::Relaxed);
-        let entry: TtData = unsafe { std::mem::transmute(entry_data) };
-
-        if entry_key ^ entry_data == board.hash() {
+        if let Some(entry) = self.tt_entry(board) {
             if entry.depth as i32 >= depth {
                 let mut score = entry.score as i32;
-                if score >= MATE_VALUE - 500 {
+                if score >= MATE_THRESHOLD {
                     score -= ply;
                 }
-                if score <= -MATE_VALUE + 500 {
+                if score <= -MATE_THRESHOLD {
                     score += ply;
                 }
-                match entry.flags {
+                match entry.bound() {
                     TtFlags::Exact => return Some(score),
                     TtFlags::Upper => {
                         if score <= lower_bound {
@@ -259,27 +778,67 @@ impl<'a> Search<'a> {
     }
 
     fn write_tt(&self, board: &Board, ply: i32, mut data: TtData) {
-        let entry = (board.hash() & ((self.tt.len() - 1) as u64)) as usize;
-        let entry = &self.tt[entry];
-        if i32::from(data.score) >= MATE_VALUE - 500 {
+        if i32::from(data.score) >= MATE_THRESHOLD {
             data.score += ply as i16;
         }
-        if i32::from(data.score) <= -MATE_VALUE + 500 {
+        if i32::from(data.score) <= -MATE_THRESHOLD {
             data.score -= ply as i16;
         }
-        let data = unsafe { std::mem::transmute::<TtData, u64>(data) };
-        entry.key.store(board.hash() ^ data, std::sync::atomic::Ordering::Relaxed);
-        entry.data.store(data, std::sync::atomic::Ordering::Relaxed);
+        data.flags |= self.tt_generation << 2;
+
+        // Overwrite this position's entry if it has one, otherwise whichever entry is shallowest once aged.
+        let entries = &tt_bucket(self.tt, board.hash()).entries;
+        let mut victim = &entries[0];
+        let mut victim_worth = i32::MAX;
+        for entry in entries {
+            let entry_key = entry.key.load(std::sync::atomic::Ordering::Relaxed);
+            let entry_data = entry.data.load(std::sync::atomic::Ordering::Relaxed);
+            let old = TtData::unpack(entry_data).unwrap_or_default();
+
+            if entry_key ^ entry_data == board.hash() {
+                // A deeper bound from this same search is worth more than a shallower one.
+                if old.age(self.tt_generation) == 0
+                    && !matches!(data.bound(), TtFlags::Exact)
+                    && i32::from(old.depth) > i32::from(data.depth) + 2
+                {
+                    return;
+                }
+                if data.m.is_none() {
+                    data.m = old.m;
+                }
+                victim = entry;
+                break;
+            }
+
+            let worth = i32::from(old.depth) - 4 * i32::from(old.age(self.tt_generation));
+            if worth < victim_worth {
+                victim = entry;
+                victim_worth = worth;
+            }
+        }
+
+        let data = data.pack();
+        victim.key.store(board.hash() ^ data, std::sync::atomic::Ordering::Relaxed);
+        victim.data.store(data, std::sync::atomic::Ordering::Relaxed);
     }
 
     #[allow(clippy::too_many_arguments)]
     fn search(
         &mut self, board: &Board, mut depth: i32, mut lower_bound: i32, upper_bound: i32, output: &mut dyn output::Output,
-        pv: &mut ArrayVec<[Move; 64]>, ply: i32, keystack: &mut Vec<u64>,
+        ply: i32, keystack: &mut Vec<u64>,
     ) -> i32 {
+        self.pv_length[ply as usize] = 0;
+        self.seldepth = self.seldepth.max(ply);
+
         // Emergency bailout
-        if ply == 63 {
-            return self.eval_with_corrhist(board, board.eval(board.side()));
+        if ply >= MAX_PLY as i32 - 1 {
+            let eval = self.static_eval(board);
+            return self.eval_with_corrhist(board, eval);
+        }
+
+        // Killers found under an earlier sibling's children say little about our children's children.
+        if let Some(grandchild) = self.stack.get_mut(ply as usize + 2) {
+            grandchild.killers = [None; 2];
         }
 
         // Draw by insufficient material
@@ -294,6 +853,12 @@ impl<'a> Search<'a> {
 
         let mut root_reduction = 0;
 
+        // When only some root moves are searched, or a move is excluded to see if it is singular, the result says nothing
+        // about the position as a whole, so it must neither come from nor go into the TT, and nothing may be pruned on the
+        // position's merits.
+        let excluded = self.stack[ply as usize].excluded;
+        let restricted = (ply == 0 && !self.root_moves.is_empty()) || excluded.is_some();
+
         // Check extension
         if board.in_check() {
             depth += 1;
@@ -301,14 +866,12 @@ impl<'a> Search<'a> {
         }
 
         if depth <= 0 {
-            return self.quiesce(board, lower_bound, upper_bound, pv, ply);
+            return self.quiesce(board, 0, lower_bound, upper_bound, ply);
         }
 
-        pv.set_len(0);
-
         let mut tt_move = None;
         if let Some(score) = self.probe_tt(board, depth, ply, lower_bound, upper_bound, &mut tt_move) {
-            if lower_bound == upper_bound - 1 {
+            if lower_bound == upper_bound - 1 && !restricted {
                 return score;
             }
         } else if lower_bound != upper_bound - 1 && tt_move.is_none() && depth >= 3 {
@@ -317,16 +880,20 @@ impl<'a> Search<'a> {
             root_reduction += 1;
         }
 
-        let eval_int = self.eval_with_corrhist(board, board.eval(board.side()));
+        let eval = self.static_eval(board);
+        let eval_int = self.eval_with_corrhist(board, eval);
+        self.stack[ply as usize].eval = (!board.in_check()).then_some(eval_int);
+        // A position that got better since our last move is pruned more readily by RFP and less so by LMP and LMR.
+        let improving = self.improving(ply);
 
-        let rfp_margin = self.params.rfp_margin_base + self.params.rfp_margin_mul * depth;
-        if !board.in_check() && depth <= 3 && eval_int - rfp_margin >= upper_bound {
+        let rfp_margin = self.params.rfp_margin_base + self.params.rfp_margin_mul * (depth - i32::from(improving));
+        if !board.in_check() && !restricted && depth <= 3 && eval_int - rfp_margin >= upper_bound {
             return eval_int - rfp_margin;
         }
 
         let razor_margin = self.params.razor_margin_mul * depth;
-        if !board.in_check() && depth <= 3 && lower_bound.abs() < 2000 && eval_int + razor_margin <= lower_bound {
-            let score = self.quiesce(board, lower_bound, lower_bound + 1, pv, ply);
+        if !board.in_check() && !restricted && depth <= 3 && lower_bound.abs() < 2000 && eval_int + razor_margin <= lower_bound {
+            let score = self.quiesce(board, 0, lower_bound, lower_bound + 1, ply);
             if score <= lower_bound {
                 return score;
             }
@@ -334,20 +901,12 @@ impl<'a> Search<'a> {
 
         let reduction = if depth > 6 { 4 } else { 3 } + ((eval_int - upper_bound) / 200).max(0);
 
-        if !board.in_check() && depth >= 2 && eval_int >= upper_bound {
+        if !board.in_check() && !restricted && depth >= 2 && eval_int >= upper_bound {
             keystack.push(board.hash());
+            self.stack[ply as usize].m = None;
+            self.stack[ply as usize + 1].double_extensions = self.stack[ply as usize].double_extensions;
             let board = board.make_null();
-            let mut child_pv = ArrayVec::new();
-            let score = -self.search(
-                &board,
-                depth - 1 - reduction,
-                -upper_bound,
-                -upper_bound + 1,
-                output,
-                &mut child_pv,
-                ply + 1,
-                keystack,
-            );
+            let score = -self.search(&board, depth - 1 - reduction, -upper_bound, -upper_bound + 1, output, ply + 1, keystack);
             keystack.pop();
 
             self.nullmove_attempts += 1;
@@ -358,70 +917,101 @@ impl<'a> Search<'a> {
             }
         }
 
-        let mut moves = ArrayVec::new();
-        board.generate(&mut moves);
-
-        // Is this checkmate or stalemate?
-        if moves.is_empty() {
-            pv.set_len(0);
-            if board.in_check() {
-                return -MATE_VALUE + ply;
-            }
-            return 0;
-        }
-
-        moves.sort_by(|a, b| {
-            // TT move sorts above everything else.
-            if let Some(tt_move) = tt_move {
-                if *a == tt_move {
-                    return Ordering::Less;
-                }
-                if *b == tt_move {
-                    return Ordering::Greater;
-                }
-            }
-
-            // Captures sort above quiet moves.
-            // Captures are sorted by most valuable victim, and tiebroken by least valuable attacker.
-            // Quiets are sorted by largest history heuristic value.
-            match (a.is_capture(), b.is_capture()) {
-                (false, false) => self.history[b.from.into_inner() as usize][b.dest.into_inner() as usize]
-                    .cmp(&self.history[a.from.into_inner() as usize][a.dest.into_inner() as usize]),
-                (false, true) => Ordering::Greater,
-                (true, false) => Ordering::Less,
-                (true, true) => board
-                    .piece_from_square(b.dest)
-                    .cmp(&board.piece_from_square(a.dest))
-                    .then_with(|| board.piece_from_square(a.from).cmp(&board.piece_from_square(b.from))),
-            }
-        });
+        let mut picker = MovePicker::new(tt_move, self.stack[ply as usize].killers, self.counter_move(ply), self.params);
+        // The moves searched so far, to be penalised if a later one causes a beta cutoff.
+        let mut tried: ArrayVec<[Move; 256]> = ArrayVec::new();
 
         let mut best_move = None;
         let mut best_score = i32::MIN;
         let mut raised_lower_bound = false;
 
+        // Singular extensions: if every move but the TT move fails low against a bound a little under the TT score, the TT
+        // move is probably the only good one, so search it deeper. If even without it the node fails high by that much,
+        // there are several good moves, so assume the node fails high (multi-cut), or at least that the TT move needn't be
+        // searched as deeply.
+        let mut tt_extension = 0;
+        if let Some((tt_move, entry)) = tt_move.zip(self.tt_entry(board)) {
+            let tt_score = i32::from(entry.score);
+            if ply > 0
+                && !restricted
+                && depth >= self.params.singular_depth
+                && i32::from(entry.depth) >= depth - 3
+                && !matches!(entry.bound(), TtFlags::Upper)
+                && tt_score.abs() < MATE_THRESHOLD
+                && board.is_legal(tt_move)
+            {
+                let singular_beta = tt_score - self.params.singular_margin * depth;
+                self.stack[ply as usize].excluded = Some(tt_move);
+                let score = self.search(board, (depth - 1) / 2, singular_beta - 1, singular_beta, output, ply, keystack);
+                self.stack[ply as usize].excluded = None;
+
+                if score < singular_beta {
+                    tt_extension = 1;
+                    if lower_bound == upper_bound - 1
+                        && score < singular_beta - self.params.double_extension_margin
+                        && self.stack[ply as usize].double_extensions < self.params.max_double_extensions
+                    {
+                        tt_extension = 2;
+                    }
+                } else if singular_beta >= upper_bound {
+                    return singular_beta;
+                } else if tt_score >= upper_bound {
+                    tt_extension = -1;
+                }
+            }
+        }
+
         // Push the move to check for repetition draws
         keystack.push(board.hash());
 
-        for (i, m) in moves.into_iter().enumerate() {
+        while let Some(m) = picker.next(self, board, ply) {
+            if Some(m) == excluded || (ply == 0 && restricted && !self.root_moves.contains(&m)) {
+                continue;
+            }
+
+            // SEE pruning: near the leaves, skip moves that lose material, once a move has been found that doesn't get mated.
+            if ply > 0
+                && !board.in_check()
+                && depth <= self.params.see_prune_depth
+                && !tried.is_empty()
+                && best_score > -MATE_THRESHOLD
+            {
+                let threshold = if m.is_capture() {
+                    -self.params.see_capture_margin * depth * depth
+                } else {
+                    -self.params.see_quiet_margin * depth
+                };
+                if 100 * board.static_exchange_evaluation(m) < threshold {
+                    continue;
+                }
+            }
+            let i = tried.len();
+            tried.push(m);
+
             self.nodes += 1;
             if lower_bound == upper_bound - 1 {
                 self.zw_nodes += 1;
             }
 
-            let mut child_pv = ArrayVec::new();
             let child_board = board.make(m);
+            self.stack[ply as usize].m = Some(StackMove { m, piece: Self::coloured_piece(board, m) });
+            let extension = if Some(m) == tt_move { tt_extension } else { 0 };
+            let child_depth = depth + extension;
+            self.stack[ply as usize + 1].double_extensions = self.stack[ply as usize].double_extensions + i32::from(extension >= 2);
             let mut score = 0;
 
             if ply == 0 {
-                let now = Instant::now();
-                if now >= self.start + Duration::from_secs(2) {
-                    output.new_move(board, depth + root_reduction, now.duration_since(self.start), self.nodes() + self.qnodes(), m);
-                }
+                output.new_move(board, depth + root_reduction, self.start.elapsed(), self.total_nodes(), m);
             }
 
-            let lmp_threshold = 1.max((3 * moves.len()) / 4);
-            if !board.in_check() && !m.is_capture() && depth == 1 && i >= lmp_threshold && best_score > -MATE_VALUE + 500 {
+            // Counting the legal moves generates any that haven't been, so it comes last.
+            if !board.in_check()
+                && !m.is_capture()
+                && depth == 1
+                && i >= 1
+                && best_score > -MATE_THRESHOLD
+                && i >= ((2 + usize::from(improving)) * picker.legal_moves(self, board, ply)) / 4
+            {
                 break;
             }
 
@@ -430,47 +1020,23 @@ impl<'a> Search<'a> {
             if depth >= 3 && i >= 4 && !board.in_check() && !m.is_capture() {
                 let depth = (depth as f32).ln();
                 let i = (i as f32).ln();
+                reduction += i32::from(!improving);
                 reduction += (depth * i).mul_add(self.params.lmr_mul, self.params.lmr_base) as i32;
                 // credit: adam
             }
 
             if i > 0 {
-                score = -self.search(
-                    &child_board,
-                    depth - reduction,
-                    -lower_bound - 1,
-                    -lower_bound,
-                    output,
-                    &mut child_pv,
-                    ply + 1,
-                    keystack,
-                );
+                score =
+                    -self.search(&child_board, child_depth - reduction, -lower_bound - 1, -lower_bound, output, ply + 1, keystack);
             }
             if i > 0 && reduction > 1 && score > lower_bound {
                 reduction = 1;
-                score = -self.search(
-                    &child_board,
-                    depth - reduction,
-                    -lower_bound - 1,
-                    -lower_bound,
-                    output,
-                    &mut child_pv,
-                    ply + 1,
-                    keystack,
-                );
+                score =
+                    -self.search(&child_board, child_depth - reduction, -lower_bound - 1, -lower_bound, output, ply + 1, keystack);
             }
             if i == 0 || lower_bound != upper_bound - 1 && score > lower_bound {
                 reduction = 1;
-                score = -self.search(
-                    &child_board,
-                    depth - reduction,
-                    -upper_bound,
-                    -lower_bound,
-                    output,
-                    &mut child_pv,
-                    ply + 1,
-                    keystack,
-                );
+                score = -self.search(&child_board, child_depth - reduction, -upper_bound, -lower_bound, output, ply + 1, keystack);
             }
 
             if score > best_score {
@@ -479,34 +1045,37 @@ impl<'a> Search<'a> {
 
                 // Ensure we have *a move* even when failing high/low at root.
                 if ply == 0 {
-                    pv.set_len(0);
-                    pv.push(m);
-                    for m in child_pv {
-                        pv.push(m);
-                    }
+                    self.update_pv(ply, m);
                 }
             }
 
-            if self.nodes.trailing_zeros() >= 10 {
-                if let Some(time) = self.stop_after {
-                    if Instant::now() >= time {
-                        keystack.pop();
-                        return best_score;
-                    }
-                }
+            // Answer a status request now rather than when the next root move starts, which can take minutes.
+            if self.status_request.is_some_and(|request| {
+                request.load(std::sync::atomic::Ordering::Relaxed) && request.swap(false, std::sync::atomic::Ordering::Relaxed)
+            }) {
+                output.status(self.start.elapsed(), self.total_nodes());
+            }
+
+            if self.should_stop() {
+                keystack.pop();
+                return best_score;
             }
 
             if score >= upper_bound {
                 let bonus = self.params.hist_bonus_mul * depth - self.params.hist_bonus_base;
                 let penalty = self.params.hist_pen_mul * depth - self.params.hist_pen_base;
-                if !m.is_capture() {
-                    for m in moves.into_iter().take(i) {
-                        if m.is_capture() {
-                            continue;
-                        }
-                        self.update_history(m, -penalty);
+                for earlier in tried.into_iter().take(i).filter(|earlier| earlier.is_capture() == m.is_capture()) {
+                    if m.is_capture() {
+                        self.update_capture_history(board, earlier, -penalty);
+                    } else {
+                        self.update_history(board, earlier, ply, -penalty);
                     }
-                    self.update_history(m, bonus);
+                }
+                if m.is_capture() {
+                    self.update_capture_history(board, m, bonus);
+                } else {
+                    self.update_history(board, m, ply, bonus);
+                    self.update_refutations(m, ply);
                 }
 
                 self.beta_cutoff_index += i as u64;
@@ -517,24 +1086,15 @@ impl<'a> Search<'a> {
 
             if score > lower_bound {
                 lower_bound = score;
-                pv.set_len(0);
-                pv.push(m);
-                for m in child_pv {
-                    pv.push(m);
-                }
+                self.update_pv(ply, m);
                 raised_lower_bound = true;
 
                 if ply == 0 {
                     let now = Instant::now();
                     if now >= self.start + Duration::from_secs(2) {
-                        output.new_pv(
-                            board,
-                            depth + root_reduction,
-                            score,
-                            now.duration_since(self.start),
-                            self.nodes() + self.qnodes(),
-                            pv,
-                        );
+                        let (time, nodes) = (now.duration_since(self.start), self.total_nodes());
+                        let pv = &self.pv_table[0][..self.pv_length[0]];
+                        output.new_pv(board, depth + root_reduction, self.seldepth, score, time, nodes, pv);
                     }
                 }
             }
@@ -542,6 +1102,22 @@ impl<'a> Search<'a> {
 
         keystack.pop();
 
+        // Is this checkmate or stalemate?
+        if tried.is_empty() {
+            // Not if the excluded move is the only legal one, which makes it singular.
+            if excluded.is_some() {
+                return lower_bound;
+            }
+            if board.in_check() {
+                return -MATE_VALUE + ply;
+            }
+            return 0;
+        }
+
+        if restricted {
+            return best_score;
+        }
+
         self.write_tt(
             board,
             ply,
@@ -549,11 +1125,11 @@ impl<'a> Search<'a> {
                 m: best_move,
                 score: best_score as i16,
                 flags: if best_score >= upper_bound {
-                    TtFlags::Lower
+                    TtFlags::Lower as u8
                 } else if raised_lower_bound {
-                    TtFlags::Exact
+                    TtFlags::Exact as u8
                 } else {
-                    TtFlags::Upper
+                    TtFlags::Upper as u8
                 },
                 depth: depth as u8,
             },
@@ -571,12 +1147,187 @@ impl<'a> Search<'a> {
         best_score
     }
 
+    /// Search `board` to `depth`, considering only `root_moves` at the root, or every move if it is empty.
     #[allow(clippy::too_many_arguments)]
     pub fn search_root(
         &mut self, board: &Board, depth: i32, lower_bound: i32, upper_bound: i32, output: &mut dyn output::Output,
-        pv: &mut ArrayVec<[Move; 64]>, keystack: &mut Vec<u64>,
+        pv: &mut Vec<Move>, keystack: &mut Vec<u64>, root_moves: &[Move],
     ) -> i32 {
-        self.search(board, depth, lower_bound, upper_bound, output, pv, 0, keystack)
+        self.root_moves.clear();
+        self.root_moves.extend_from_slice(root_moves);
+        self.seldepth = 0;
+        let score = self.search(board, depth, lower_bound, upper_bound, output, 0, keystack);
+        self.flush_nodes();
+        pv.clear();
+        pv.extend_from_slice(&self.pv_table[0][..self.pv_length[0]]);
+        score
+    }
+
+    /// Make the PV at `ply` be `m` followed by the PV one ply deeper.
+    fn update_pv(&mut self, ply: i32, m: Move) {
+        let ply = ply as usize;
+        let child_length = self.pv_length[ply + 1];
+        let (row, child_rows) = self.pv_table[ply..].split_first_mut().unwrap();
+        row[0] = m;
+        row[1..=child_length].copy_from_slice(&child_rows[0][..child_length]);
+        self.pv_length[ply] = child_length + 1;
+    }
+
+    /// Look for a forced mate in at most `moves` moves by the side to move, returning its score if there is one.
+    ///
+    /// Nothing is pruned or reduced, so unless the search was stopped, `None` means there is no such mate.
+    /// The mate found is not necessarily the shortest one; search with increasing `moves` for that.
+    pub fn search_mate(&mut self, board: &Board, moves: i32, pv: &mut Vec<Move>) -> Option<i32> {
+        assert!(2 * moves <= MAX_PLY as i32, "mate in {moves} is too deep to search");
+        let mated = self.mate_attack(board, moves, 0);
+        self.flush_nodes();
+        pv.clear();
+        if mated {
+            pv.extend_from_slice(&self.pv_table[0][..self.pv_length[0]]);
+        }
+        (mated && !self.shared.stopped()).then_some(MATE_VALUE - (2 * moves - 1))
+    }
+
+    /// Whether the side to move can force mate in at most `moves` moves.
+    fn mate_attack(&mut self, board: &Board, moves: i32, ply: i32) -> bool {
+        self.pv_length[ply as usize] = 0;
+        let mut list = ArrayVec::new();
+        board.generate(&mut list);
+
+        // Checks are the likeliest mating moves, so try them first.
+        let children = list.iter().map(|m| (*m, board.make(*m))).collect::<Vec<_>>();
+        let (checks, quiets): (Vec<_>, Vec<_>) = children.into_iter().partition(|(_, child)| child.in_check());
+
+        // The last move has to give check to mate.
+        let quiets = if moves > 1 { quiets } else { Vec::new() };
+
+        for (m, child) in checks.into_iter().chain(quiets) {
+            self.nodes += 1;
+            if self.should_stop() {
+                return false;
+            }
+
+            if self.mate_defend(&child, moves, ply + 1) {
+                self.update_pv(ply, m);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether every move of the side to move allows a mate within the `moves` the attacker had before this one.
+    fn mate_defend(&mut self, board: &Board, moves: i32, ply: i32) -> bool {
+        self.pv_length[ply as usize] = 0;
+        let mut list = ArrayVec::new();
+        board.generate(&mut list);
+
+        if list.is_empty() {
+            return board.in_check();
+        }
+
+        if moves == 1 {
+            return false;
+        }
+
+        for m in &list {
+            self.nodes += 1;
+            if !self.mate_attack(&board.make(*m), moves - 1, ply + 1) {
+                return false;
+            }
+
+            // Show the defence that holds out longest.
+            if self.pv_length[ply as usize] == 0 || self.pv_length[ply as usize + 1] >= self.pv_length[ply as usize] {
+                self.update_pv(ply, *m);
+            }
+        }
+        true
+    }
+
+    /// Check the clock every so often and the hard node limit at every node, returning whether the search should stop.
+    fn should_stop(&mut self) -> bool {
+        if self.nodes.trailing_zeros() >= 10 {
+            self.flush_nodes();
+            if let Some(time) = self.stop_after {
+                let now = Instant::now();
+                if self.shared.pondering() {
+                    self.stop_after = Some(now + (time - self.start));
+                    self.start = now;
+                } else if now >= time {
+                    self.shared.stop();
+                }
+            }
+        }
+        self.node_limit_reached()
+    }
+
+    /// Check the hard node limit, returning whether the search should stop; quiescence checks only this.
+    fn node_limit_reached(&self) -> bool {
+        if self.hard_nodes.is_some_and(|limit| self.total_nodes() >= limit) {
+            self.shared.stop();
+        }
+        self.shared.stopped()
+    }
+
+    /// Add the nodes searched since the last flush to the shared node count.
+    fn flush_nodes(&mut self) {
+        let nodes = self.nodes + self.qnodes;
+        self.shared.nodes.fetch_add(nodes - self.flushed_nodes, std::sync::atomic::Ordering::Relaxed);
+        self.flushed_nodes = nodes;
+    }
+
+    /// Whether this search, or another thread sharing its state, has run out of time or been told to stop.
+    #[must_use]
+    pub fn stopped(&self) -> bool {
+        self.shared.stopped()
+    }
+
+    /// When the search started, or when pondering last ended.
+    #[must_use]
+    pub const fn start(&self) -> Instant {
+        self.start
+    }
+
+    /// Whether this search is thinking on the opponent's time.
+    #[must_use]
+    pub fn pondering(&self) -> bool {
+        self.shared.pondering()
+    }
+
+    /// Nodes searched by every thread sharing this search's state.
+    #[must_use]
+    pub fn total_nodes(&self) -> u64 {
+        self.shared.nodes.load(std::sync::atomic::Ordering::Relaxed) + self.nodes + self.qnodes - self.flushed_nodes
+    }
+
+    /// How much of the TT this search has written to, in permille, estimated from its first thousand entries.
+    #[must_use]
+    pub fn hashfull(&self) -> u32 {
+        let sample = self.tt.iter().take(250).flat_map(|bucket| &bucket.entries);
+        let (mut entries, mut used) = (0, 0);
+        for entry in sample {
+            let key = entry.key.load(std::sync::atomic::Ordering::Relaxed);
+            let data = entry.data.load(std::sync::atomic::Ordering::Relaxed);
+            entries += 1;
+            if key != 0 && TtData::unpack(data).is_some_and(|data| data.age(self.tt_generation) == 0) {
+                used += 1;
+            }
+        }
+        if entries == 0 {
+            return 0;
+        }
+        used * 1000 / entries
+    }
+
+    /// Whether the soft node limit has been reached, so no new iteration should be started.
+    #[must_use]
+    pub fn soft_node_limit_reached(&self) -> bool {
+        self.soft_nodes.is_some_and(|limit| self.total_nodes() >= limit)
+    }
+
+    /// The deepest ply reached by the last call to `search_root`.
+    #[must_use]
+    pub const fn seldepth(&self) -> i32 {
+        self.seldepth
     }
 
     #[must_use]
@@ -604,6 +1355,11 @@ impl<'a> Search<'a> {
         (self.q_beta_cutoff_index as f64) / (self.q_beta_cutoffs as f64)
     }
 
+    #[must_use]
+    pub fn eval_cache_hits(&self) -> f64 {
+        100.0 * (self.eval_cache_hits as f64) / (self.eval_cache_probes as f64)
+    }
+
     #[must_use]
     pub fn zw_nodes(&self) -> f64 {
         100.0 * (self.zw_nodes as f64) / (self.nodes as f64)
@@ -614,3 +1370,191 @@ impl<'a> Search<'a> {
         100.0 * (self.zw_qnodes as f64) / (self.qnodes as f64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Instant};
+
+    use tinyvec::ArrayVec;
+    use yukari_movegen::{Board, Move};
+
+    use super::{
+        allocate_eval_cache, allocate_tt, load_tt, save_tt, History, Search, SearchParams, SharedSearch, TtBucket, MATE_THRESHOLD,
+        MATE_VALUE,
+    };
+
+    /// Search for mates of increasing length up to `moves`, returning the first found and its line.
+    fn solve(fen: &str, moves: i32) -> Option<(i32, Vec<Move>)> {
+        let board = Board::from_fen(fen).unwrap();
+        let tt = allocate_tt(1);
+        let eval_cache = allocate_eval_cache(1);
+        let mut history = History::new();
+        let mut corrhist = Box::new([[0; 16384]; 2]);
+        let params = SearchParams::default();
+        let shared = SharedSearch::default();
+        let mut s = Search::new(Instant::now(), None, &tt, &eval_cache, &mut history, &mut corrhist, &params, &shared);
+        (1..=moves).find_map(|n| {
+            let mut pv = Vec::new();
+            s.search_mate(&board, n, &mut pv).map(|score| (score, pv))
+        })
+    }
+
+    /// Check that `fen` is mate in exactly `moves`, and that the line found ends in checkmate.
+    fn assert_mate_in(fen: &str, moves: i32) {
+        let (score, pv) = solve(fen, moves).expect("mate not found");
+        assert_eq!(score, MATE_VALUE - (2 * moves - 1));
+
+        let mut board = Board::from_fen(fen).unwrap();
+        for m in pv {
+            board = board.make(m);
+        }
+        let mut list = ArrayVec::new();
+        board.generate(&mut list);
+        assert!(board.in_check() && list.is_empty());
+    }
+
+    #[test]
+    fn mate_in_2() {
+        assert_mate_in("kbK5/pp6/1P6/8/8/8/8/R7 w - - 0 1", 2);
+        assert_mate_in("r2qkb1r/pp2nppp/3p4/2pNN1B1/2BnP3/3P4/PPP2PPP/R2bK2R w KQkq - 1 1", 2);
+        assert_mate_in("6k1/pp4p1/2p5/2bp4/8/P5Pb/1P3rrP/2BRRN1K b - - 0 1", 2);
+    }
+
+    #[test]
+    fn mate_in_3() {
+        assert_mate_in("r1b1kb1r/pppp1ppp/5q2/4n3/3KP3/2N3PN/PPP4P/R1BQ1B1R b kq - 0 1", 3);
+        assert_mate_in("2r3k1/p4p2/3Rp2p/1p2P1pK/8/1P4P1/P3Q2P/1q6 b - - 0 1", 3);
+        assert_mate_in("3q1r1k/2p4p/1p1pBrp1/p2Pp3/2PnP3/5PP1/PP1Q2K1/5R1R w - - 1 1", 3);
+    }
+
+    #[test]
+    fn mate_in_4() {
+        assert_mate_in("r1bk3r/pppq1ppp/5n2/4N1N1/2Bp4/Bn6/P4PPP/4R1K1 w - - 1 1", 4);
+    }
+
+    #[test]
+    fn no_mate() {
+        assert!(solve("r1bk3r/pppq1ppp/5n2/4N1N1/2Bp4/Bn6/P4PPP/4R1K1 w - - 1 1", 3).is_none());
+        assert!(solve("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", 2).is_none());
+    }
+
+    /// Quiescence-search `fen` with a full window and fresh state.
+    fn quiesce(fen: &str, params: &SearchParams) -> i32 {
+        let board = Board::from_fen(fen).unwrap();
+        let tt = allocate_tt(1);
+        let eval_cache = allocate_eval_cache(1);
+        let mut history = History::new();
+        let mut corrhist = Box::new([[0; 16384]; 2]);
+        let shared = SharedSearch::default();
+        let mut s = Search::new(Instant::now(), None, &tt, &eval_cache, &mut history, &mut corrhist, params, &shared);
+        s.quiesce(&board, 0, -MATE_VALUE, MATE_VALUE, 0)
+    }
+
+    #[test]
+    fn quiesce_sees_checkmate() {
+        // Standing pat would score these as merely material down.
+        assert_eq!(quiesce("R5k1/5ppp/8/8/8/8/8/6K1 b - - 0 1", &SearchParams::default()), -MATE_VALUE);
+        assert_eq!(quiesce("6rk/5Npp/8/8/8/8/8/6K1 b - - 0 1", &SearchParams::default()), -MATE_VALUE);
+    }
+
+    #[test]
+    fn quiesce_escapes_check() {
+        // No capture gets out of check, but the king can.
+        assert!(quiesce("R5k1/5pp1/8/8/8/8/8/6K1 b - - 0 1", &SearchParams::default()) > -MATE_THRESHOLD);
+    }
+
+    #[test]
+    fn quiesce_finds_mate_by_quiet_check() {
+        let fen = "6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1";
+        assert!(quiesce(fen, &SearchParams::default()) < MATE_THRESHOLD);
+        let params = SearchParams { qsearch_checks: true, ..SearchParams::default() };
+        assert_eq!(quiesce(fen, &params), MATE_VALUE - 1);
+    }
+
+    /// Search `fen` to `depth` with `tt` and otherwise fresh state, returning the score and PV.
+    fn search_with(tt: &[TtBucket], fen: &str, depth: i32) -> (i32, Vec<String>) {
+        let board = Board::from_fen(fen).unwrap();
+        let eval_cache = allocate_eval_cache(1);
+        let mut history = History::new();
+        let mut corrhist = Box::new([[0; 16384]; 2]);
+        let params = SearchParams::default();
+        let shared = SharedSearch::default();
+        let mut s = Search::new(Instant::now(), None, tt, &eval_cache, &mut history, &mut corrhist, &params, &shared);
+        let mut pv = Vec::new();
+        let score = s.search_root(&board, depth, -MATE_VALUE, MATE_VALUE, &mut crate::output::NoOp, &mut pv, &mut Vec::new(), &[]);
+        (score, pv.iter().map(ToString::to_string).collect())
+    }
+
+    /// The number of entries in use in `tt`.
+    fn used_entries(tt: &[TtBucket]) -> usize {
+        let mut saved = Vec::new();
+        save_tt(tt, &mut saved).unwrap();
+        saved[20..].chunks_exact(16).filter(|entry| entry.iter().any(|&b| b != 0)).count()
+    }
+
+    const TT_FEN: &str = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 10";
+
+    #[test]
+    fn reloaded_tt_reproduces_search() {
+        let tt = allocate_tt(1);
+        search_with(&tt, TT_FEN, 6);
+        let mut saved = Vec::new();
+        save_tt(&tt, &mut saved).unwrap();
+
+        let reloaded = allocate_tt(1);
+        load_tt(&reloaded, &mut Cursor::new(saved)).unwrap();
+        assert_eq!(search_with(&tt, TT_FEN, 7), search_with(&reloaded, TT_FEN, 7));
+    }
+
+    #[test]
+    fn tt_reloads_into_different_size() {
+        let tt = allocate_tt(1);
+        search_with(&tt, TT_FEN, 6);
+        let mut saved = Vec::new();
+        save_tt(&tt, &mut saved).unwrap();
+
+        let resized = allocate_tt(3);
+        load_tt(&resized, &mut Cursor::new(saved)).unwrap();
+        assert!(used_entries(&tt) > 0);
+        assert_eq!(used_entries(&tt), used_entries(&resized));
+    }
+
+    #[test]
+    fn tt_rejects_other_files() {
+        let tt = allocate_tt(1);
+        assert!(load_tt(&tt, &mut Cursor::new(b"not a hash table at all")).is_err());
+    }
+
+    #[test]
+    fn tt_rejects_wrong_bucket_count() {
+        let tt = allocate_tt(1);
+        let mut saved = Vec::new();
+        save_tt(&tt, &mut saved).unwrap();
+
+        // A count whose entries overflow a u64 must not be trusted either.
+        for buckets in [tt.len() as u64 + 1, u64::MAX / 4 + 1] {
+            saved[12..20].copy_from_slice(&buckets.to_le_bytes());
+            assert!(load_tt(&tt, &mut Cursor::new(&saved)).is_err());
+        }
+    }
+
+    #[test]
+    fn tt_drops_corrupt_entries() {
+        let tt = allocate_tt(1);
+        search_with(&tt, TT_FEN, 6);
+        let mut saved = Vec::new();
+        save_tt(&tt, &mut saved).unwrap();
+
+        // Give every other entry with a move an impossible move type.
+        let mut corrupted = 0;
+        for entry in saved[20..].chunks_exact_mut(16).filter(|entry| entry[12..] != [0xFF; 4] && entry != &[0; 16]).step_by(2) {
+            entry[14] = 7;
+            corrupted += 1;
+        }
+
+        let reloaded = allocate_tt(1);
+        load_tt(&reloaded, &mut Cursor::new(saved)).unwrap();
+        assert!(corrupted > 0);
+        assert_eq!(used_entries(&reloaded), used_entries(&tt) - corrupted);
+    }
+}